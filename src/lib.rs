@@ -0,0 +1,5095 @@
+use std::{collections::{BTreeMap, HashMap, HashSet, VecDeque}, env, fmt, fs, io::{BufRead, IsTerminal, Read, Seek, SeekFrom, Write}, path::{Path, PathBuf}, sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering}, time::{Duration, Instant, SystemTime}};
+use serde::{Deserialize, Deserializer, Serialize};
+use once_cell::sync::{Lazy, OnceCell};
+use std::sync::{Arc, RwLock};
+use std::time::UNIX_EPOCH;
+use chrono::{DateTime, Utc, Local};
+use tokio::sync::watch;
+use tracing::{debug, debug_span, error, info, info_span, instrument, warn};
+use xxhash_rust::xxh3::{xxh3_64, Xxh3};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use globset::{Glob, GlobBuilder, GlobSet, GlobSetBuilder};
+use notify::{EventKind, RecursiveMode, Watcher as _};
+use owo_colors::{OwoColorize, Style};
+use rayon::prelude::*;
+use futures_util::SinkExt;
+use flate2::{write::GzEncoder, Compression};
+use tokio_tungstenite::{MaybeTlsStream, WebSocketStream};
+use tokio_tungstenite::tungstenite::{client::IntoClientRequest, http::{HeaderName, HeaderValue}, Message};
+
+/// Polling interval used when the config doesn't specify a usable one
+const DEFAULT_POLL_INTERVAL_MS: u64 = 1000;
+
+/// Bodies smaller than this are sent as they are, even with `compress_payloads`
+const COMPRESS_THRESHOLD_BYTES: usize = 1024;
+
+#[derive(Serialize, Deserialize)]
+#[serde(default)]
+pub struct Config {
+    /// Files to watch, by extension like `rs`, exact name or glob
+    pub targets: Vec<String>,
+    /// Match the targets regardless of case, so `txt` matches `README.TXT`
+    pub case_insensitive_targets: bool,
+    /// Where the notifications are posted: http(s):// and ws(s):// URLs,
+    /// `unix:` sockets, or tables with their own `format` and `targets`
+    pub endpoints: Vec<Endpoint>,
+    /// Time between two polls of the watched files, the default 1000 when zero
+    pub poll_interval_ms: u64,
+    /// Poll less often while nothing changes, doubling the interval after
+    /// `idle_cycles_before_backoff` quiet polls up to `max_poll_interval_ms`.
+    /// The first change found brings it back to `poll_interval_ms`
+    pub adaptive_poll: bool,
+    /// Longest time between two polls with `adaptive_poll`
+    pub max_poll_interval_ms: u64,
+    /// Polls in a row that find nothing before the interval grows
+    pub idle_cycles_before_backoff: u32,
+    /// Most time the first poll is put off by, a random amount of it, so
+    /// watchers started together don't poll in step. None when zero
+    pub startup_jitter_ms: u64,
+    /// Only report files whose contents changed, not just their mtime. An
+    /// unchanged size and mtime is then trusted without reading the file again
+    pub use_content_hash: bool,
+    /// Report changes to the permissions and the owner of files, only whether
+    /// they're read-only outside of unix
+    pub watch_metadata: bool,
+    /// Patterns left out of the watch, in the .gitignore syntax
+    pub ignore: Vec<String>,
+    /// Folders to watch, relative to the roots, everything when empty
+    pub include_dirs: Vec<String>,
+    /// Folders never to watch, relative to the roots, even when included
+    pub exclude_dirs: Vec<String>,
+    /// Files never to watch, by their exact path, absolute or relative to the roots
+    pub exclude_files: Vec<String>,
+    /// Leave out what the .gitignore at the top of each root ignores
+    pub respect_gitignore: bool,
+    /// Watch hidden files and folders too: dotfiles, and the ones with the
+    /// hidden attribute on Windows
+    pub watch_hidden: bool,
+    /// `poll` the files every `poll_interval_ms`, or react to the `events`
+    /// the OS reports for them
+    pub backend: String,
+    /// Bearer token sent to the endpoints in the `Authorization` header
+    pub auth_token: Option<String>,
+    /// Extra headers sent to the endpoints with every request
+    pub headers: BTreeMap<String, String>,
+    /// Where the notifications come from, sent along with each of them. The
+    /// machine's hostname when unset
+    pub source: Option<String>,
+    /// PEM certificate presented to the endpoints, along with `client_key_path`
+    pub client_cert_path: Option<PathBuf>,
+    /// PEM PKCS#8 private key of `client_cert_path`
+    pub client_key_path: Option<PathBuf>,
+    /// PEM certificate trusted on top of the system's, for private authorities
+    pub ca_cert_path: Option<PathBuf>,
+    /// Retries of a delivery that failed before it's given up on
+    pub retry_count: u32,
+    /// Wait before the first retry, doubling with every retry after it
+    pub retry_base_ms: u64,
+    /// Time a single request may take before it's given up on, no limit when zero
+    pub request_timeout_ms: u64,
+    /// Most requests sent to the endpoints per second, later ones wait their turn
+    pub max_requests_per_second: Option<u32>,
+    /// Send the notifications that queued up while delivering together, in one request
+    pub batch_notifications: bool,
+    /// Most notifications, or batches, being delivered at once. Above one they
+    /// can reach the endpoints in a different order than they happened
+    pub max_concurrent_deliveries: usize,
+    /// File the requests that failed for good are appended to, as JSON lines,
+    /// to be sent again on the next start
+    pub dead_letter_path: Option<String>,
+    /// Files the JSON of the notifications is appended to, one per line,
+    /// each getting the ones of the files its pattern matches
+    pub file_sinks: Vec<FileSink>,
+    /// Seconds between two heartbeats posted to the endpoints taking JSON,
+    /// telling that the watcher is alive while nothing changes. None when unset
+    pub heartbeat_interval_secs: Option<u64>,
+    /// Stop with an error when a root is deleted or can't be read anymore,
+    /// instead of waiting for it to come back
+    pub exit_on_root_lost: bool,
+    /// Notifications `Watcher::notifications` holds until they're read
+    pub stream_buffer: usize,
+    /// What `Watcher::notifications` does once its buffer is full: `block`
+    /// the watcher until the stream is read, or `drop_oldest`
+    pub stream_backpressure: String,
+    /// Wait for the roots that don't exist yet to be created, instead of
+    /// refusing to start
+    pub wait_for_root: bool,
+    /// Template file the JSON endpoints get each notification rendered with
+    /// instead, e.g. for chat webhooks. Has `{path}`, `{time}`, `{kind}`,
+    /// `{diff}`, `{added}` and `{removed}`, and `{diff | json}` to quote them.
+    /// Literal braces are escaped, as in `\{ "text": {diff | json} }`
+    pub payload_template: Option<PathBuf>,
+    /// Content type of the rendered `payload_template`
+    pub payload_content_type: String,
+    /// Gzip the bodies posted to the endpoints once they're over 1KB
+    pub compress_payloads: bool,
+    /// Files above this size are never read, their changes are reported
+    /// without a diff. Even with `use_content_hash` they're told by mtime and
+    /// size alone, so touching one is reported as a change
+    pub max_file_size_bytes: u64,
+    /// Most files watched across the roots, the first ones by path. The others
+    /// are left out, and taken in without being reported once there's room
+    /// for them. None for no limit
+    pub max_watched_files: Option<usize>,
+    /// Most bytes the old and new contents of a file may have together to be
+    /// diffed, larger changes are reported without a diff. No limit when zero
+    pub max_diff_bytes: usize,
+    /// Bytes read from a file at a time, its contents are built up line by line
+    pub read_buffer_bytes: usize,
+    /// Leave the files found on startup unread until they first change, that
+    /// change being reported without a diff. Speeds up starting on large trees
+    pub lazy_content: bool,
+    /// Globs of files that only ever grow, like logs. Only the lines appended
+    /// to them are read and reported, whatever their size
+    pub tail_patterns: Vec<String>,
+    /// Extensions or globs of files whose changes are reported without their
+    /// contents, like `.env`. They're never read, so even with
+    /// `use_content_hash` their changes are told by mtime and size alone.
+    /// They have to be watched in the first place: dotfiles like `.env` are
+    /// only with `watch_hidden`
+    pub no_diff_targets: Vec<String>,
+    /// Write paths relative to the root, e.g. `src/main.rs`, rather than
+    /// absolute. Only with a single root, which the paths can't be mistaken on
+    pub relative_paths: bool,
+    /// Send the whole new contents of created and modified files along with
+    /// the diff, as `content`. Files without them, like too large or binary
+    /// ones, have `content_omitted` set instead
+    pub include_full_content: bool,
+    /// strftime pattern of the times printed, the JSON always has RFC 3339
+    pub time_format: String,
+    /// Zone of the times printed: `local`, `utc` or an IANA name like `Europe/Paris`
+    pub timezone: String,
+    /// How diffs are printed: `simple`, the changed lines, or `unified`
+    pub diff_format: String,
+    /// What modifications are diffed against: `snapshot`, the contents last
+    /// seen, or `git_head`, the committed contents when the file is tracked
+    pub diff_against: String,
+    /// How lines are matched up: `lcs`, or `myers` and `patience` which are
+    /// faster on large files and give more readable hunks
+    pub diff_algorithm: String,
+    /// Unchanged lines shown before and after every change, in both formats
+    pub context_lines: usize,
+    /// Most lines of a diff printed or sent, the rest is cut off. No limit when zero
+    pub max_diff_lines: usize,
+    /// Highlight the words that changed within the changed lines
+    pub word_diff: bool,
+    /// Describe changes to JSON, TOML and YAML files as the keys that changed,
+    /// like `a.b.c: 1 -> 2`, instead of the lines
+    pub semantic_diff: bool,
+    /// Fewest added plus removed lines a modification needs to be reported
+    pub min_changed_lines: usize,
+    /// Leave out modifications that only add, remove or move whitespace
+    pub ignore_whitespace_only: bool,
+    /// Leave out modifications bringing a file back to contents it had within
+    /// this many seconds, like undoing and redoing. None when unset
+    pub dedup_window_secs: Option<u64>,
+    /// Most earlier versions of each file remembered for `dedup_window_secs`
+    pub dedup_history: usize,
+    /// Color the console output: `auto` when it's a terminal, `always` or `never`
+    pub color: String,
+    /// Width of the tree printed by `--once`, the terminal's width when unset
+    pub tree_width: Option<usize>,
+    /// Descend into the folders links point to, each folder being watched once
+    pub follow_symlinks: bool,
+    /// Levels of folders descended below each root, deeper folders are left
+    /// as leaves. Unlimited when unset
+    pub max_depth: Option<usize>,
+    /// Keep the folders without anything to watch in the trees, instead of
+    /// looking into them again on every poll until they have
+    pub include_empty_dirs: bool,
+    /// Scan the entries of large folders on several threads at startup
+    pub parallel_scan: bool,
+    /// Remember the files across restarts, reporting what changed while the
+    /// watcher wasn't running
+    pub persist_state: bool,
+    /// Where the state is kept, `.watcher-state.json` in the current directory by default
+    pub state_path: Option<String>,
+    /// Address to serve `/healthz` and `/status` on, e.g. `127.0.0.1:9997`
+    pub status_addr: Option<String>,
+    /// Address to serve Prometheus metrics on at `/metrics`, e.g. `127.0.0.1:9998`
+    pub metrics_addr: Option<String>,
+    /// Address to stream the notifications on at `/events`, as Server-Sent Events
+    pub sse_addr: Option<String>,
+    /// Shell command run when files change, with `WATCHER_PATH`, `WATCHER_KIND`,
+    /// `WATCHER_TIME`, `WATCHER_SEQ` and `WATCHER_DIFF` set for the change
+    pub on_change_command: Option<String>,
+    /// Quiet time the changes have to be followed by for the command to run,
+    /// once for the last of them. Every change runs it when zero
+    pub on_change_debounce_ms: u64,
+    /// What's printed for every notification: `human`, `json` lines or `both`
+    pub output: String,
+    /// Print nothing but errors, the notifications are still delivered
+    pub quiet: bool,
+    /// Report the files being watched once the trees are first filled
+    pub notify_on_start: bool,
+    /// Report every file found on startup as created, with all of its lines.
+    /// What changed while the watcher wasn't running isn't reported on top
+    pub emit_initial_snapshot: bool,
+    /// Log what would be sent to the endpoints instead of sending it
+    pub dry_run: bool,
+    /// Directories or single files to watch, the current directory when empty. The paths
+    /// given on the command line take their place
+    pub roots: Vec<PathBuf>
+}
+
+impl Default for Config {
+    /// Generate the default configuration
+    fn default() -> Self {
+        Config {
+            targets: vec![
+                "txt".to_string(),
+                "json".to_string(),
+                "toml".to_string(),
+                "rs".to_string(),
+            ],
+            case_insensitive_targets: true,
+            endpoints: vec![
+                Endpoint::new("http://localhost:9996")
+            ],
+            poll_interval_ms: DEFAULT_POLL_INTERVAL_MS,
+            adaptive_poll: false,
+            max_poll_interval_ms: 30_000,
+            idle_cycles_before_backoff: 10,
+            startup_jitter_ms: 0,
+            use_content_hash: false,
+            watch_metadata: false,
+            ignore: vec![
+                ".git/".to_string(),
+                "target/".to_string(),
+                "node_modules/".to_string(),
+            ],
+            include_dirs: Vec::new(),
+            exclude_dirs: Vec::new(),
+            exclude_files: Vec::new(),
+            respect_gitignore: false,
+            watch_hidden: false,
+            backend: "poll".to_string(),
+            auth_token: None,
+            headers: BTreeMap::new(),
+            source: None,
+            client_cert_path: None,
+            client_key_path: None,
+            ca_cert_path: None,
+            retry_count: 3,
+            retry_base_ms: 500,
+            request_timeout_ms: 10_000,
+            max_requests_per_second: None,
+            batch_notifications: false,
+            max_concurrent_deliveries: 1,
+            dead_letter_path: None,
+            file_sinks: Vec::new(),
+            heartbeat_interval_secs: None,
+            exit_on_root_lost: false,
+            stream_buffer: 1024,
+            stream_backpressure: "block".to_string(),
+            wait_for_root: false,
+            payload_template: None,
+            payload_content_type: "application/json".to_string(),
+            compress_payloads: false,
+            max_file_size_bytes: 1024 * 1024 * 10,
+            max_watched_files: None,
+            max_diff_bytes: 0,
+            read_buffer_bytes: 64 * 1024,
+            lazy_content: false,
+            tail_patterns: Vec::new(),
+            no_diff_targets: Vec::new(),
+            relative_paths: true,
+            include_full_content: false,
+            time_format: "%Y-%m-%d %H:%M:%S".to_string(),
+            timezone: "local".to_string(),
+            diff_format: "simple".to_string(),
+            diff_against: "snapshot".to_string(),
+            diff_algorithm: "lcs".to_string(),
+            context_lines: 3,
+            max_diff_lines: 0,
+            word_diff: false,
+            semantic_diff: false,
+            min_changed_lines: 0,
+            ignore_whitespace_only: false,
+            dedup_window_secs: None,
+            dedup_history: 8,
+            color: "auto".to_string(),
+            tree_width: None,
+            follow_symlinks: false,
+            max_depth: None,
+            include_empty_dirs: false,
+            parallel_scan: false,
+            persist_state: false,
+            state_path: None,
+            status_addr: None,
+            metrics_addr: None,
+            sse_addr: None,
+            on_change_command: None,
+            on_change_debounce_ms: 300,
+            output: "human".to_string(),
+            quiet: false,
+            notify_on_start: false,
+            emit_initial_snapshot: false,
+            dry_run: false,
+            roots: Vec::new()
+        }
+    }
+}
+
+/// Explanation of every setting written along with it by `save_commented`,
+/// and an example for the ones that are unset by default
+const CONFIG_DOCS: &[(&str, &str, Option<&str>)] = &[
+    ("targets", "Files to watch, by extension (`rs`), exact name or glob", None),
+    ("case_insensitive_targets", "Match the targets regardless of case, so `txt` matches `README.TXT`", None),
+    ("endpoints", "Where notifications are posted: http(s):// or ws(s):// URLs, unix: sockets, or tables\nwith a `url`, a `format` of json, unified or simple and the `targets`\nwhose notifications they get", None),
+    ("poll_interval_ms", "Time between two polls of the watched files, the default 1000 when zero", None),
+    ("adaptive_poll", "Poll less often while nothing changes, back to `poll_interval_ms` on the first change", None),
+    ("max_poll_interval_ms", "Longest time between two polls with `adaptive_poll`", None),
+    ("idle_cycles_before_backoff", "Polls in a row that find nothing before `adaptive_poll` doubles the interval", None),
+    ("startup_jitter_ms", "Most time the first poll is put off by, a random amount of it, so watchers started together spread out", None),
+    ("use_content_hash", "Only report files whose contents changed, not just their mtime", None),
+    ("watch_metadata", "Report changes to the permissions and the owner of files", None),
+    ("ignore", "Patterns left out of the watch, in the .gitignore syntax", None),
+    ("include_dirs", "Folders to watch, relative to the roots, everything when empty", None),
+    ("exclude_dirs", "Folders never to watch, relative to the roots", None),
+    ("exclude_files", "Files never to watch, by their exact path, absolute or relative to the roots", None),
+    ("respect_gitignore", "Leave out what the .gitignore of each root ignores", None),
+    ("watch_hidden", "Watch hidden files and folders too, like dotfiles", None),
+    ("backend", "`poll` the files, or react to the `events` reported by the OS", None),
+    ("auth_token", "Bearer token sent to the endpoints", Some("\"secret\"")),
+    ("headers", "Extra headers sent to the endpoints", None),
+    ("source", "Where the notifications come from, sent along with each of them, the hostname when unset", Some("\"build-server\"")),
+    ("client_cert_path", "PEM certificate presented to the endpoints, for mutual TLS", Some("\"client.crt\"")),
+    ("client_key_path", "PEM PKCS#8 private key of the client certificate", Some("\"client.key\"")),
+    ("ca_cert_path", "PEM certificate trusted on top of the system's", Some("\"ca.crt\"")),
+    ("retry_count", "Retries of a failed delivery", None),
+    ("retry_base_ms", "Wait before the first retry, doubling with every retry after it", None),
+    ("request_timeout_ms", "Time a single request may take, no limit when zero", None),
+    ("max_requests_per_second", "Most requests sent to the endpoints per second", Some("10")),
+    ("batch_notifications", "Send the notifications that queued up together in one request", None),
+    ("max_concurrent_deliveries", "Most notifications or batches delivered at once, above one they may arrive out of order", None),
+    ("heartbeat_interval_secs", "Seconds between two heartbeats posted to the json endpoints, telling that the watcher is alive", Some("60")),
+    ("exit_on_root_lost", "Stop with an error when a root is deleted or can't be read, instead of waiting for it to come back", None),
+    ("stream_buffer", "Notifications the library's notification stream holds until they're read", None),
+    ("stream_backpressure", "What the notification stream does once its buffer is full: `block` or `drop_oldest`", None),
+    ("wait_for_root", "Wait for the roots that don't exist yet to be created, instead of refusing to start", None),
+    ("dead_letter_path", "File the requests that failed for good are kept in, to be sent again on the next start", Some("\"failed-notifications.ndjson\"")),
+    ("file_sinks", "Files the JSON of the notifications is appended to, one per line, by the `pattern`\n(an extension or a glob) of the changed files. Reopened when rotated", Some("[{ pattern = \"rs\", path = \"rust-changes.log\" }]")),
+    ("payload_template", "Template file the JSON endpoints get each notification rendered with instead,\nwith {path}, {time}, {kind}, {diff}, {added} and {removed}. Literal braces are\nescaped, e.g. for Slack: \\{ \"text\": {diff | json} }", Some("\"slack.tpl\"")),
+    ("payload_content_type", "Content type of the rendered payload_template", None),
+    ("compress_payloads", "Gzip the request bodies over 1KB", None),
+    ("max_file_size_bytes", "Files above this size are never read and reported without a diff,\ntheir changes told by mtime and size alone even with use_content_hash", None),
+    ("max_watched_files", "Most files watched across the roots, the ones found past it are left out", Some("100000")),
+    ("max_diff_bytes", "Changes whose old and new contents are together larger are reported without a diff, no limit when zero", None),
+    ("read_buffer_bytes", "Bytes read from a file at a time", None),
+    ("lazy_content", "Read the files found on startup only once they change, the first change being reported without a diff", None),
+    ("tail_patterns", "Globs of files that only grow, like logs, whose appended lines are reported", None),
+    ("no_diff_targets", "Extensions or globs of files reported without their contents, which are never read.\nDotfiles like .env are only watched with watch_hidden", Some("[\"env\", \"*.pem\"]")),
+    ("relative_paths", "Write paths relative to the root rather than absolute, with a single root only", None),
+    ("include_full_content", "Send the whole new contents of created and modified files in the JSON, as `content`", None),
+    ("time_format", "strftime pattern of the times printed, the JSON always has RFC 3339", None),
+    ("timezone", "Zone of the times printed: `local`, `utc` or an IANA name", Some("\"Europe/Paris\"")),
+    ("diff_format", "How diffs are printed: `simple` or `unified`", None),
+    ("diff_against", "Diff modifications against the `snapshot` last seen or the `git_head` commit", None),
+    ("diff_algorithm", "How lines are matched up: `lcs`, `myers` or `patience`", None),
+    ("context_lines", "Unchanged lines shown around the changes, in simple and unified diffs", None),
+    ("max_diff_lines", "Most lines of a diff printed or sent, no limit when zero", None),
+    ("word_diff", "Highlight the changed words within the changed lines", None),
+    ("semantic_diff", "Show changes to JSON, TOML and YAML files as the keys that changed, like `a.b.c: 1 -> 2`", None),
+    ("min_changed_lines", "Fewest added plus removed lines a modification needs to be reported", None),
+    ("dedup_window_secs", "Leave out modifications bringing a file back to contents it had within this many seconds", Some("30")),
+    ("dedup_history", "Most earlier versions of each file remembered for dedup_window_secs", None),
+    ("ignore_whitespace_only", "Leave out modifications that only touch whitespace", None),
+    ("color", "Color the console output: `auto`, `always` or `never`", None),
+    ("tree_width", "Width of the tree printed by --once, the terminal's width when unset", Some("120")),
+    ("follow_symlinks", "Descend into linked folders", None),
+    ("max_depth", "Levels of folders descended below each root, unlimited when unset", Some("3")),
+    ("include_empty_dirs", "Keep the folders without anything to watch in the trees", None),
+    ("parallel_scan", "Scan large folders on several threads at startup", None),
+    ("persist_state", "Report what changed while the watcher wasn't running", None),
+    ("state_path", "Where the state is kept", Some("\".watcher-state.json\"")),
+    ("status_addr", "Address to serve /healthz and /status on", Some("\"127.0.0.1:9997\"")),
+    ("metrics_addr", "Address to serve Prometheus metrics on at /metrics", Some("\"127.0.0.1:9998\"")),
+    ("sse_addr", "Address to stream the notifications on at /events, as Server-Sent Events", Some("\"127.0.0.1:9999\"")),
+    ("on_change_command", "Shell command run when files change, with WATCHER_PATH, WATCHER_KIND,\nWATCHER_TIME, WATCHER_SEQ and WATCHER_DIFF set", Some("\"cargo build\"")),
+    ("on_change_debounce_ms", "Quiet time after the changes before the command runs once for the last of them", None),
+    ("output", "What's printed for every notification: `human`, `json` or `both`", None),
+    ("quiet", "Print nothing but errors, the notifications are still delivered", None),
+    ("notify_on_start", "Report the files being watched on startup", None),
+    ("emit_initial_snapshot", "Report every file found on startup as created, with all of its lines", None),
+    ("dry_run", "Log what would be sent to the endpoints instead of sending it", None),
+    ("roots", "Directories or single files to watch, the current directory when empty", None)
+];
+
+/// Anything the watcher can fail at, for callers that want to tell the
+/// cases apart
+#[derive(Debug, thiserror::Error)]
+pub enum WatcherError {
+    #[error("config: {0}")]
+    Config(#[from] ConfigError),
+    #[error("{}: {source}", .path.display())]
+    File { path: PathBuf, #[source] source: FileError },
+    #[error(transparent)]
+    Delivery(#[from] DeliveryError),
+    #[error("{} was deleted or can't be read anymore", .0.display())]
+    RootLost(PathBuf),
+    #[error("no saved state to check against at {}", .0.display())]
+    NoState(PathBuf),
+    #[error("cannot save the state to {}: {reason}", .path.display())]
+    SaveState { path: PathBuf, reason: String },
+    #[error("cannot watch the files for events: {0}")]
+    Watch(#[from] notify::Error),
+    #[error("a different config is in effect already, there's one per process")]
+    ConfigInUse,
+    /// There are no roots, and the current directory to watch instead is gone
+    #[error("cannot get the current directory to watch: {0}")]
+    CurrentDir(#[source] std::io::Error)
+}
+
+/// Why the config file couldn't be used or saved. A missing file isn't one
+/// of them, the defaults are used then
+#[derive(Debug, thiserror::Error)]
+pub enum ConfigError {
+    #[error("cannot read it: {0}")]
+    Read(#[source] std::io::Error),
+    #[error("cannot write it: {0}")]
+    Write(#[source] std::io::Error),
+    /// The file isn't valid TOML, JSON or YAML or has the wrong types, the
+    /// message points at the line
+    #[error("{}", .0.trim_end())]
+    Parse(String),
+    /// The config couldn't be turned into the format it's saved in
+    #[error("cannot serialize it: {0}")]
+    Serialize(String),
+    #[error("{0}")]
+    Invalid(String)
+}
+
+/// Formats the config can be written in, told apart by the file's extension
+#[derive(PartialEq)]
+enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml
+}
+
+impl ConfigFormat {
+    /// TOML unless the extension says otherwise
+    fn of(path: &Path) -> ConfigFormat {
+        match path.extension().and_then(|ext| ext.to_str()).map(str::to_lowercase).as_deref() {
+            Some("json") => ConfigFormat::Json,
+            Some("yaml" | "yml") => ConfigFormat::Yaml,
+            _ => ConfigFormat::Toml
+        }
+    }
+}
+
+impl Config {
+    /// Time to sleep between two polls, falling back to the default
+    /// instead of busy-looping when the interval is zero
+    fn poll_interval(&self) -> Duration {
+        match self.poll_interval_ms {
+            0 => Duration::from_millis(DEFAULT_POLL_INTERVAL_MS),
+            ms => Duration::from_millis(ms)
+        }
+    }
+
+    /// Time until the next poll once `idle` polls in a row found nothing,
+    /// which only grows with `adaptive_poll`
+    fn idle_poll_interval(&self, idle: u32) -> Duration {
+        let base = self.poll_interval();
+        if !self.adaptive_poll || idle < self.idle_cycles_before_backoff {
+            return base;
+        }
+
+        let doublings = (idle - self.idle_cycles_before_backoff + 1).min(16);
+        (base * 2u32.pow(doublings)).min(Duration::from_millis(self.max_poll_interval_ms).max(base))
+    }
+
+    /// Save the config to a file, in the format of its extension
+    pub fn save(&self, path: &Path) -> Result<(), ConfigError> {
+        let serialize = |e: &dyn fmt::Display| ConfigError::Serialize(e.to_string());
+        let config_str = match ConfigFormat::of(path) {
+            ConfigFormat::Toml => toml::to_string(self).map_err(|e| serialize(&e))?,
+            ConfigFormat::Json => serde_json::to_string_pretty(self).map_err(|e| serialize(&e))? + "\n",
+            ConfigFormat::Yaml => serde_yaml::to_string(self).map_err(|e| serialize(&e))?
+        };
+        fs::write(path, config_str).map_err(ConfigError::Write)
+    }
+
+    /// Whether `save_commented` can explain the settings in the file, which
+    /// only TOML has comments for
+    pub fn can_comment(path: &Path) -> bool {
+        ConfigFormat::of(path) == ConfigFormat::Toml
+    }
+
+    /// Save the config to a file with every setting explained, the unset
+    /// ones commented out with an example
+    pub fn save_commented(&self, path: &Path) -> Result<(), ConfigError> {
+        let mut table = toml::Table::try_from(self).map_err(|e| ConfigError::Serialize(e.to_string()))?;
+        let mut text = String::new();
+
+        for (key, doc, example) in CONFIG_DOCS {
+            for line in doc.lines() {
+                text.push_str(&format!("# {}\n", line));
+            }
+
+            match (table.remove(*key), example) {
+                (Some(value), _) => text.push_str(&format!("{} = {}\n\n", key, value)),
+                (None, Some(example)) => text.push_str(&format!("# {} = {}\n\n", key, example)),
+                (None, None) => text.push('\n')
+            }
+        }
+
+        // Anything that isn't documented yet still gets saved
+        for (key, value) in table {
+            text.push_str(&format!("{} = {}\n", key, value));
+        }
+
+        fs::write(path, text.trim_end().to_string() + "\n").map_err(ConfigError::Write)
+    }
+
+    /// Reads the config file, TOML, JSON or YAML by its extension, with the
+    /// `WATCHER_*` environment variables taking precedence over it
+    pub fn load(path: &Path) -> Result<Config, ConfigError> {
+        // Try to read the config file, running with the defaults without one
+        let mut config = match fs::read_to_string(path) {
+            Ok(contents) => match ConfigFormat::of(path) {
+                ConfigFormat::Toml => toml::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))?,
+                ConfigFormat::Json => serde_json::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))?,
+                ConfigFormat::Yaml => serde_yaml::from_str(&contents).map_err(|e| ConfigError::Parse(e.to_string()))?
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                info!(path = %path.display(), "no config file, using the defaults");
+                Config::default()
+            },
+            Err(e) => return Err(ConfigError::Read(e)),
+        };
+
+        config.apply_env().map_err(ConfigError::Invalid)?;
+        config.validate().map_err(ConfigError::Invalid)?;
+        Ok(config)
+    }
+
+    /// Check the settings that would otherwise only fail, or be quietly
+    /// replaced, once the watcher is running. The error names the key at fault
+    pub fn validate(&self) -> Result<(), String> {
+        if self.endpoints.is_empty() {
+            return Err("endpoints: at least one endpoint is needed".to_string());
+        }
+
+        for endpoint in &self.endpoints {
+            if let Some(path) = endpoint.url.strip_prefix("unix:") {
+                if !cfg!(unix) {
+                    return Err(format!("endpoints: {:?}, Unix sockets aren't available on this platform", endpoint.url));
+                }
+                if path.is_empty() {
+                    return Err(format!("endpoints: {:?} should name the socket, like unix:/run/watcher.sock", endpoint.url));
+                }
+                // One notification per line, so the bodies have to fit on one
+                if endpoint.format != "json" {
+                    return Err(format!("endpoints: {} only takes the json format", endpoint.url));
+                }
+                continue;
+            }
+
+            let url = reqwest::Url::parse(&endpoint.url)
+                .map_err(|e| format!("endpoints: {:?} is not a valid URL: {}", endpoint.url, e))?;
+            if !matches!(url.scheme(), "http" | "https" | "ws" | "wss") {
+                return Err(format!("endpoints: {:?} should start with http://, https://, ws://, wss:// or unix:", endpoint.url));
+            }
+
+            if !matches!(endpoint.format.as_str(), "json" | "unified" | "simple") {
+                return Err(format!("endpoints: unknown format {:?} for {}, expected json, unified or simple", endpoint.format, endpoint.url));
+            }
+        }
+
+        for sink in &self.file_sinks {
+            if sink.pattern.is_empty() {
+                return Err(format!("file_sinks: the pattern for {} is empty", sink.path.display()));
+            }
+            Glob::new(&sink.pattern)
+                .map_err(|e| format!("file_sinks: invalid pattern {:?}: {}", sink.pattern, e))?;
+        }
+
+        if let Some(path) = &self.payload_template {
+            let template = fs::read_to_string(path)
+                .map_err(|e| format!("payload_template: cannot read {}: {}", path.display(), e))?;
+            tinytemplate::TinyTemplate::new().add_template("payload", &template)
+                .map_err(|e| format!("payload_template: {}", e))?;
+        }
+
+        let items = chrono::format::StrftimeItems::new(&self.time_format);
+        if items.into_iter().any(|item| matches!(item, chrono::format::Item::Error)) {
+            return Err(format!("time_format: {:?} is not a valid strftime pattern", self.time_format));
+        }
+
+        DisplayZone::parse(&self.timezone).map_err(|e| format!("timezone: {}", e))?;
+
+        if self.adaptive_poll && Duration::from_millis(self.max_poll_interval_ms) < self.poll_interval() {
+            return Err(format!("max_poll_interval_ms: should be at least the poll interval ({} ms)", self.poll_interval().as_millis()));
+        }
+
+        if self.heartbeat_interval_secs == Some(0) {
+            return Err("heartbeat_interval_secs: should be greater than zero, or left out for no heartbeats".to_string());
+        }
+
+        if self.max_concurrent_deliveries == 0 {
+            return Err("max_concurrent_deliveries: should be greater than zero".to_string());
+        }
+
+        if self.max_requests_per_second == Some(0) {
+            return Err("max_requests_per_second: should be greater than zero, or left out for no limit".to_string());
+        }
+
+        if self.client_cert_path.is_some() != self.client_key_path.is_some() {
+            return Err("client_cert_path: needs client_key_path as well, and the other way around".to_string());
+        }
+
+        // The certificates are only read once the first notification is
+        // sent, better find out about broken ones right away
+        if self.client_cert_path.is_some() || self.ca_cert_path.is_some() {
+            http_client(self)?;
+            ws_tls(self)?;
+        }
+
+        if self.stream_buffer == 0 {
+            return Err("stream_buffer: should be greater than zero".to_string());
+        }
+
+        if !matches!(self.stream_backpressure.as_str(), "block" | "drop_oldest") {
+            return Err(format!("stream_backpressure: unknown behavior {:?}, expected block or drop_oldest", self.stream_backpressure));
+        }
+
+        if !matches!(self.backend.as_str(), "poll" | "events") {
+            return Err(format!("backend: unknown backend {:?}, expected poll or events", self.backend));
+        }
+
+        if !matches!(self.diff_against.as_str(), "snapshot" | "git_head") {
+            return Err(format!("diff_against: unknown baseline {:?}, expected snapshot or git_head", self.diff_against));
+        }
+
+        if !matches!(self.diff_algorithm.as_str(), "lcs" | "myers" | "patience") {
+            return Err(format!("diff_algorithm: unknown algorithm {:?}, expected lcs, myers or patience", self.diff_algorithm));
+        }
+
+        if !matches!(self.output.as_str(), "human" | "json" | "both") {
+            return Err(format!("output: unknown output {:?}, expected human, json or both", self.output));
+        }
+
+        Ok(())
+    }
+
+    /// Override the settings given in the environment, lists are comma separated
+    fn apply_env(&mut self) -> Result<(), String> {
+        let var = |name: &str| env::var(name).ok().filter(|value| !value.is_empty());
+        let list = |value: String| -> Vec<String> {
+            value.split(',').map(str::trim).filter(|item| !item.is_empty()).map(str::to_string).collect()
+        };
+
+        if let Some(value) = var("WATCHER_ENDPOINTS") {
+            self.endpoints = list(value).iter().map(|url| Endpoint::new(url)).collect();
+        }
+
+        if let Some(value) = var("WATCHER_TARGETS") {
+            self.targets = list(value);
+        }
+
+        if let Some(value) = var("WATCHER_POLL_INTERVAL_MS") {
+            self.poll_interval_ms = value.parse()
+                .map_err(|e| format!("WATCHER_POLL_INTERVAL_MS: {}", e))?;
+        }
+
+        if let Some(value) = var("WATCHER_AUTH_TOKEN") {
+            self.auth_token = Some(value);
+        }
+
+        if let Some(value) = var("WATCHER_SOURCE") {
+            self.source = Some(value);
+        }
+
+        Ok(())
+    }
+}
+
+/// A place notifications are delivered to, along with the body it expects:
+/// `json`, `unified` or `simple`
+#[derive(Serialize)]
+pub struct Endpoint {
+    pub url: String,
+    pub format: String,
+    /// Files whose notifications are sent here, written like the config's
+    /// `targets`. Every file when empty
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    pub targets: Vec<String>,
+    #[serde(skip)]
+    filter: TargetFilter
+}
+
+/// The files a place notifications go to gets, compiled on first use
+#[derive(Default)]
+struct TargetFilter(OnceCell<GlobSet>);
+
+impl TargetFilter {
+    /// Whether the notification is about a file the patterns match, any file
+    /// when there are none. The ones about a whole root go everywhere
+    fn wants(&self, patterns: &[String], notif: &Notification) -> bool {
+        if patterns.is_empty() || matches!(notif.kind, NotificationKind::Started { .. }) {
+            return true;
+        }
+
+        let filter = self.0.get_or_init(|| build_targets(patterns, CONFIG.load().case_insensitive_targets));
+        notif.path.file_name().is_some_and(|name| filter.is_match(name))
+    }
+}
+
+impl Endpoint {
+    pub fn new(url: &str) -> Self {
+        Endpoint { url: url.to_string(), format: "json".to_string(), targets: Vec::new(), filter: TargetFilter::default() }
+    }
+
+    /// Whether the notification is meant for this endpoint
+    fn wants(&self, notif: &Notification) -> bool {
+        self.filter.wants(&self.targets, notif)
+    }
+
+    /// Serialize the notifications the way this endpoint expects them,
+    /// returning the body along with its content type
+    fn body(&self, notifs: &[&Notification], batch: bool) -> (String, String) {
+        let text = |render: fn(&Notification) -> String| {
+            notifs.iter().map(|notif| render(notif)).collect::<Vec<String>>().join("\n")
+        };
+
+        match self.format.as_str() {
+            "unified" => (text(Notification::unified_diff), "text/x-diff".to_string()),
+            "simple" => (text(|notif| notif.simple(false)), "text/plain; charset=utf-8".to_string()),
+            _ if TEMPLATE.get().is_some() => (text(Notification::templated), CONFIG.load().payload_content_type.clone()),
+            _ if batch => {
+                let batch: Vec<serde_json::Value> = notifs.iter().map(|notif| notif.value()).collect();
+                (serde_json::to_string(&batch).unwrap(), "application/json".to_string())
+            },
+            _ => (text(Notification::json), "application/json".to_string())
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Endpoint {
+    /// Accept a bare URL as well, which gets JSON bodies
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum Repr {
+            Url(String),
+            Full { url: String, format: Option<String>, #[serde(default)] targets: Vec<String> }
+        }
+
+        Ok(match Repr::deserialize(deserializer)? {
+            Repr::Url(url) => Endpoint::new(&url),
+            Repr::Full { url, format, targets } => Endpoint {
+                format: format.unwrap_or_else(|| "json".to_string()),
+                targets,
+                filter: TargetFilter::default(),
+                url
+            }
+        })
+    }
+}
+
+/// A file the JSON of some notifications is appended to, one per line
+#[derive(Serialize, Deserialize)]
+pub struct FileSink {
+    /// Files whose notifications are written here, an extension or a glob
+    /// like the config's `targets`
+    pub pattern: String,
+    pub path: PathBuf,
+    #[serde(skip)]
+    filter: TargetFilter
+}
+
+impl FileSink {
+    pub fn new(pattern: &str, path: &Path) -> Self {
+        FileSink { pattern: pattern.to_string(), path: path.to_path_buf(), filter: TargetFilter::default() }
+    }
+
+    /// Whether the notification is written here
+    fn wants(&self, notif: &Notification) -> bool {
+        self.filter.wants(std::slice::from_ref(&self.pattern), notif)
+    }
+}
+
+/// The configuration handed to the first `Watcher`, the default one until
+/// then, and the one given to `reload` after that. A replaced config is freed
+/// once the last of its users is done with it
+static SETTINGS: RwLock<Option<Arc<Config>>> = RwLock::new(None);
+
+/// Bumped on every reload, so the values built from the config are built again
+static GENERATION: AtomicU64 = AtomicU64::new(0);
+
+/// The configuration in effect
+static CONFIG: CurrentConfig = CurrentConfig;
+
+struct CurrentConfig;
+
+impl CurrentConfig {
+    /// The config in effect, which stays whole while it's held even if
+    /// another one is put in effect meanwhile
+    fn load(&self) -> Arc<Config> {
+        if let Some(config) = &*SETTINGS.read().unwrap() {
+            return config.clone();
+        }
+        SETTINGS.write().unwrap().get_or_insert_with(Arc::default).clone()
+    }
+}
+
+/// The configuration in effect, e.g. for the settings a caller of the
+/// library reads on every notification
+pub fn current_config() -> Arc<Config> {
+    CONFIG.load()
+}
+
+/// Use the config unless one is in effect already, which it has to be the
+/// same as, there being one config per process
+fn settle(config: Config) -> Result<(), WatcherError> {
+    let mut settings = SETTINGS.write().unwrap();
+    match &*settings {
+        None => {
+            *settings = Some(Arc::new(config));
+            Ok(())
+        },
+        Some(current) if serde_json::to_value(&**current).ok() == serde_json::to_value(&config).ok() => Ok(()),
+        Some(_) => Err(WatcherError::ConfigInUse)
+    }
+}
+
+/// A value built from the config on first use, and built again once the
+/// config has been reloaded. A replaced value is freed like the configs
+struct Reloadable<T> {
+    build: fn() -> T,
+    built: RwLock<Option<(u64, Arc<T>)>>
+}
+
+impl<T> Reloadable<T> {
+    const fn new(build: fn() -> T) -> Self {
+        Reloadable { build, built: RwLock::new(None) }
+    }
+
+    /// The value built from the config in effect
+    fn get(&self) -> Arc<T> {
+        let generation = GENERATION.load(Ordering::Acquire);
+        if let Some((built, value)) = &*self.built.read().unwrap() {
+            if *built == generation {
+                return value.clone();
+            }
+        }
+
+        let mut built = self.built.write().unwrap();
+        match &*built {
+            Some((built, value)) if *built == generation => value.clone(),
+            _ => {
+                let value = Arc::new((self.build)());
+                *built = Some((generation, value.clone()));
+                value
+            }
+        }
+    }
+}
+
+/// Sent once a config is waiting to be put in effect, for the watcher to
+/// report what changed under the current one first
+static RELOADED: Lazy<watch::Sender<()>> = Lazy::new(|| watch::channel(()).0);
+
+/// The config given to `reload`, until the watcher puts it in effect
+static PENDING: std::sync::Mutex<Option<Config>> = std::sync::Mutex::new(None);
+
+/// Put a new config in effect while watching. The changes found under the
+/// current one are reported first, then the trees are built again with the
+/// targets and the ignore rules of the new one, keeping the roots being
+/// watched. The settings only read on startup, listed in the README, like
+/// `backend` or the addresses served on, need a restart. An invalid config
+/// is turned down, keeping the current one
+pub fn reload(mut config: Config) -> Result<(), ConfigError> {
+    config.validate().map_err(ConfigError::Invalid)?;
+    config.roots = CONFIG.load().roots.clone();
+    *PENDING.lock().unwrap() = Some(config);
+
+    // Without a watcher running there's nothing to report first
+    if RELOADED.receiver_count() == 0 {
+        apply_reload();
+    } else {
+        RELOADED.send_replace(());
+    }
+    Ok(())
+}
+
+/// Put the config waiting since `reload` in effect, telling whether there was one
+fn apply_reload() -> bool {
+    let Some(config) = PENDING.lock().unwrap().take() else {
+        return false
+    };
+
+    *SETTINGS.write().unwrap() = Some(Arc::new(config));
+    GENERATION.fetch_add(1, Ordering::Release);
+    info!("config reloaded");
+    true
+}
+
+/// Name of the instance in the payloads, for when several send to the same place
+static SOURCE: Reloadable<String> = Reloadable::new(|| {
+    CONFIG.load().source.clone().unwrap_or_else(|| {
+        hostname::get().map(|name| name.to_string_lossy().into_owned()).unwrap_or_else(|e| {
+            warn!(error = %e, "failed to get the hostname, leaving the source empty");
+            String::new()
+        })
+    })
+});
+
+/// The zone the times are printed in
+enum DisplayZone {
+    Local,
+    Utc,
+    Named(chrono_tz::Tz)
+}
+
+impl DisplayZone {
+    fn parse(name: &str) -> Result<DisplayZone, String> {
+        match name.to_ascii_lowercase().as_str() {
+            "local" => Ok(DisplayZone::Local),
+            "utc" => Ok(DisplayZone::Utc),
+            _ => name.parse().map(DisplayZone::Named)
+                .map_err(|_| format!("unknown zone {:?}, expected local, utc or an IANA name", name))
+        }
+    }
+}
+
+static DISPLAY_ZONE: Reloadable<DisplayZone> = Reloadable::new(|| {
+    DisplayZone::parse(&CONFIG.load().timezone).unwrap_or_else(|e| {
+        warn!(error = %e, "falling back to the local time");
+        DisplayZone::Local
+    })
+});
+
+/// Contents of `payload_template`, read once
+static TEMPLATE: Reloadable<Option<String>> = Reloadable::new(|| {
+    let config = CONFIG.load();
+    let path = config.payload_template.as_ref()?;
+    fs::read_to_string(path)
+        .map_err(|e| error!(path = %path.display(), error = %e, "failed to read the payload template, sending JSON"))
+        .ok()
+});
+
+/// Render a template with the given values. Values are written as they are,
+/// or JSON encoded with the `json` formatter
+fn render_template(template: &str, context: &serde_json::Value) -> Result<String, String> {
+    let mut engine = tinytemplate::TinyTemplate::new();
+    engine.set_default_formatter(&tinytemplate::format_unescaped);
+    engine.add_formatter("json", |value, output| {
+        output.push_str(&value.to_string());
+        Ok(())
+    });
+    engine.add_template("payload", template).map_err(|e| e.to_string())?;
+    engine.render("payload", context).map_err(|e| e.to_string())
+}
+
+/// Whether the console output gets colored, `auto` leaves it up to `NO_COLOR`
+/// and stdout being a terminal
+static COLOR: Reloadable<bool> = Reloadable::new(|| {
+    match CONFIG.load().color.as_str() {
+        "always" => true,
+        "never" => false,
+        _ => env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && std::io::stdout().is_terminal()
+    }
+});
+
+/// Apply a style to the text when coloring is asked for
+fn paint(text: &str, style: Style, color: bool) -> String {
+    if color {
+        text.style(style).to_string()
+    } else {
+        text.to_string()
+    }
+}
+
+/// Compile target patterns matched against file names. A bare token like
+/// `rs` doesn't contain any glob syntax, it matches both the `*.rs` extension
+/// and a file with that exact name
+fn build_targets(patterns: &[String], case_insensitive: bool) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+
+    for pattern in patterns {
+        let candidates = if pattern.contains(['*', '?', '[', '{']) {
+            vec![pattern.clone()]
+        } else {
+            vec![format!("*.{}", pattern), pattern.clone()]
+        };
+
+        for candidate in candidates {
+            match GlobBuilder::new(&candidate).case_insensitive(case_insensitive).build() {
+                Ok(glob) => { builder.add(glob); },
+                Err(e) => warn!(pattern, error = %e, "ignoring invalid target")
+            }
+        }
+    }
+
+    builder.build().unwrap_or_else(|_| GlobSet::empty())
+}
+
+static TARGETS: Reloadable<GlobSet> = Reloadable::new(|| build_targets(&CONFIG.load().targets, CONFIG.load().case_insensitive_targets));
+
+/// Files that are tailed, matched against their name or their whole path
+static TAIL: Reloadable<GlobSet> = Reloadable::new(|| {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in &CONFIG.load().tail_patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => { builder.add(glob); },
+            Err(e) => warn!(pattern, error = %e, "ignoring invalid tail pattern")
+        }
+    }
+
+    builder.build().unwrap_or_else(|_| GlobSet::empty())
+});
+
+/// Files whose contents are kept to themselves, matched like the targets
+static NO_DIFF: Reloadable<GlobSet> = Reloadable::new(|| build_targets(&CONFIG.load().no_diff_targets, CONFIG.load().case_insensitive_targets));
+
+/// Whether the file's changes are reported without reading it
+fn is_diff_suppressed(path: &Path) -> bool {
+    !NO_DIFF.get().is_empty() && path.file_name().is_some_and(|name| NO_DIFF.get().is_match(name))
+}
+
+/// Whether only the lines appended to the file are reported
+fn is_tailed(path: &Path) -> bool {
+    !TAIL.get().is_empty() && (TAIL.get().is_match(path) || path.file_name().is_some_and(|name| TAIL.get().is_match(name)))
+}
+
+/// Matchers built per root from the configured ignore patterns, followed by
+/// the root's `.gitignore` when `respect_gitignore` is set and its
+/// `.watcherignore`. Later patterns win, so a `!pattern` in `.watcherignore`
+/// brings back what the others ignored
+static IGNORE: Reloadable<Vec<Gitignore>> = Reloadable::new(|| {
+    CONFIG.load().roots.iter().map(|root| {
+        let mut builder = GitignoreBuilder::new(root);
+
+        for pattern in &CONFIG.load().ignore {
+            if let Err(e) = builder.add_line(None, pattern) {
+                warn!(pattern, error = %e, "ignoring invalid ignore pattern");
+            }
+        }
+
+        let gitignore = root.join(".gitignore");
+        if CONFIG.load().respect_gitignore && gitignore.is_file() {
+            if let Some(e) = builder.add(&gitignore) {
+                warn!(path = %gitignore.display(), error = %e, "failed to read some ignore patterns");
+            }
+        }
+
+        let watcherignore = root.join(".watcherignore");
+        if watcherignore.is_file() {
+            if let Some(e) = builder.add(&watcherignore) {
+                warn!(path = %watcherignore.display(), error = %e, "failed to read some ignore patterns");
+            }
+        }
+
+        builder.build().unwrap_or_else(|e| {
+            warn!(root = %root.display(), error = %e, "failed to build the ignore rules, ignoring nothing");
+            Gitignore::empty()
+        })
+    }).collect()
+});
+
+/// Whether the file or folder is hidden: a dotfile, or on Windows one with
+/// the hidden attribute
+fn is_hidden(path: &Path) -> bool {
+    if path.file_name().is_some_and(|name| name.to_string_lossy().starts_with('.')) {
+        return true;
+    }
+
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+        if path.symlink_metadata().is_ok_and(|m| m.file_attributes() & FILE_ATTRIBUTE_HIDDEN != 0) {
+            return true;
+        }
+    }
+
+    false
+}
+
+/// The paths of `exclude_files` in the config in effect
+static EXCLUDED_FILES: Reloadable<HashSet<PathBuf>> = Reloadable::new(|| {
+    let config = CONFIG.load();
+    excluded_paths(&config.exclude_files, &config.roots)
+});
+
+/// The absolute paths of the files, with the relative ones taken under
+/// every root. Either separator works on every platform
+fn excluded_paths(files: &[String], roots: &[PathBuf]) -> HashSet<PathBuf> {
+    files.iter().flat_map(|file| {
+        let file = PathBuf::from(file.replace('\\', "/").trim_start_matches("./"));
+        if file.is_absolute() {
+            vec![file.canonicalize().unwrap_or(file)]
+        } else {
+            roots.iter().map(|root| root.join(&file)).collect()
+        }
+    }).collect()
+}
+
+/// Whether a path lies within `include_dirs`, outside of `exclude_dirs` and
+/// isn't one of `exclude_files`. The folders leading to an included one are
+/// in scope too, so it can be reached
+fn in_scope(path: &Path, is_dir: bool) -> bool {
+    if !is_dir && EXCLUDED_FILES.get().contains(path) {
+        return false;
+    }
+
+    let config = CONFIG.load();
+    match config.roots.iter().find_map(|root| path.strip_prefix(root).ok()) {
+        Some(relative) => in_dirs(relative, is_dir, &config.include_dirs, &config.exclude_dirs),
+        None => true
+    }
+}
+
+/// Whether a path relative to its root lies within the included folders and
+/// outside of the excluded ones, every folder being included when there are none
+fn in_dirs(relative: &Path, is_dir: bool, include_dirs: &[String], exclude_dirs: &[String]) -> bool {
+    let dir = |dir: &String| Path::new(dir.trim_start_matches("./")).to_path_buf();
+
+    if exclude_dirs.iter().any(|excluded| relative.starts_with(dir(excluded))) {
+        return false;
+    }
+
+    include_dirs.is_empty() || include_dirs.iter().any(|included| {
+        relative.starts_with(dir(included)) || (is_dir && dir(included).starts_with(relative))
+    })
+}
+
+/// Whether the ignore rules of the root the path lives under leave it out.
+/// The folders it's in are only looked at with `parents`, as walking the
+/// tree prunes the ignored ones already
+fn is_ignored(path: &Path, is_dir: bool, parents: bool) -> bool {
+    let matchers = IGNORE.get();
+    matchers.iter().find(|matcher| path.starts_with(matcher.path())).is_some_and(|matcher| match parents {
+        true => matcher.matched_path_or_any_parents(path, is_dir).is_ignore(),
+        false => matcher.matched(path, is_dir).is_ignore()
+    })
+}
+
+/// Columns the tree is printed in, from the config, the terminal or a
+/// fallback for when the output isn't one
+static TREE_WIDTH: Reloadable<usize> = Reloadable::new(|| {
+    CONFIG.load().tree_width
+        .or_else(|| terminal_size::terminal_size().map(|(width, _)| width.0 as usize))
+        .unwrap_or(120)
+});
+
+/// Columns taken by the modification time written after each name
+const TREE_TIME_WIDTH: usize = 40;
+
+/// Columns the names get however narrow the tree is
+const TREE_MIN_NAME_WIDTH: usize = 20;
+
+/// Folders with at least this many entries are scanned in parallel when
+/// `parallel_scan` is set, below that the threads cost more than they save
+const PARALLEL_SCAN_MIN_ENTRIES: usize = 16;
+
+/// Threads shared by every parallel scan, one per core at most
+static SCAN_POOL: Lazy<rayon::ThreadPool> = Lazy::new(|| {
+    let threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(threads)
+        .thread_name(|index| format!("watcher-scan-{}", index))
+        .build()
+        .expect("Failed to create the scan thread pool")
+});
+
+/// Why an entry was left out of the tree
+enum Skip {
+    /// Ignored or not a target, there's no need to look at it again
+    Permanently,
+    /// A folder without anything to watch, at least for now
+    ForNow
+}
+
+#[derive(Debug)]
+pub enum NodeType {
+    File, 
+    Folder
+}
+
+/// Why a file's contents couldn't be diffed
+#[derive(Debug, thiserror::Error)]
+pub enum FileError {
+    #[error("cannot open it: {0}")]
+    Open(#[source] std::io::Error),
+    #[error("cannot read its metadata: {0}")]
+    Metadata(#[source] std::io::Error),
+    #[error("cannot read it: {0}")]
+    Read(#[source] std::io::Error),
+    #[error("it's above the size cap")]
+    TooLarge,
+    /// The contents have null bytes early on or aren't valid UTF-8, so the
+    /// file is treated as binary and only its digest is kept
+    #[error("it's binary")]
+    Binary { hash: String }
+}
+
+/// Bytes at the start of a file looked through for null bytes
+const BINARY_SNIFF_BYTES: usize = 8 * 1024;
+
+/// Contents of the file in the `HEAD` commit of its repository, nothing when
+/// it isn't tracked or git isn't around
+fn committed_contents(path: &Path) -> Option<String> {
+    let folder = path.parent()?;
+    let name = path.file_name()?.to_str()?;
+
+    let output = std::process::Command::new("git")
+        .arg("show")
+        .arg(format!("HEAD:./{}", name))
+        .current_dir(folder)
+        .stderr(std::process::Stdio::null())
+        .output()
+        .map_err(|e| debug!(path = %path.display(), error = %e, "failed to run git"))
+        .ok()?;
+
+    if !output.status.success() {
+        return None;
+    }
+    String::from_utf8(output.stdout).ok()
+}
+
+pub struct Node {
+    kind: NodeType,
+    path: PathBuf,
+    name: String,
+    elapsed: Option<u128>,
+    size: Option<u64>,
+    hash: Option<String>,
+    children: Vec<Node>,
+    skipped: HashSet<PathBuf>,
+    content: Option<String>,
+    /// Number of folders between the root and this node
+    depth: usize,
+    /// How far a tailed file was read, nothing for the other files
+    tail: Option<Tail>,
+    /// Permissions and owner of a file, kept with `watch_metadata`
+    access: Option<Access>,
+    /// The file was last read as binary, so only its digest is known
+    binary: bool,
+    /// A linked folder, kept as a leaf without `follow_symlinks`
+    link: bool,
+    /// Digests of the contents the file had recently and when it left them,
+    /// oldest first, for `dedup_window_secs`
+    recent: VecDeque<(u64, Instant)>
+}
+
+/// Who owns a file and who may do what with it, as far as the platform tells
+#[derive(Clone, Copy, PartialEq)]
+struct Access {
+    #[cfg(unix)]
+    mode: u32,
+    #[cfg(unix)]
+    uid: u32,
+    #[cfg(unix)]
+    gid: u32,
+    #[cfg(not(unix))]
+    readonly: bool
+}
+
+impl Access {
+    #[cfg(unix)]
+    fn of(metadata: &fs::Metadata) -> Access {
+        use std::os::unix::fs::MetadataExt;
+        Access { mode: metadata.mode() & 0o7777, uid: metadata.uid(), gid: metadata.gid() }
+    }
+
+    #[cfg(not(unix))]
+    fn of(metadata: &fs::Metadata) -> Access {
+        Access { readonly: metadata.permissions().readonly() }
+    }
+
+    /// What changed since `old`, e.g. `mode 644 -> 755`
+    #[cfg(unix)]
+    fn changes_from(&self, old: &Access) -> String {
+        let mut changes = Vec::new();
+        if self.mode != old.mode {
+            changes.push(format!("mode {:o} -> {:o}", old.mode, self.mode));
+        }
+        if self.uid != old.uid {
+            changes.push(format!("uid {} -> {}", old.uid, self.uid));
+        }
+        if self.gid != old.gid {
+            changes.push(format!("gid {} -> {}", old.gid, self.gid));
+        }
+        changes.join(", ")
+    }
+
+    #[cfg(not(unix))]
+    fn changes_from(&self, old: &Access) -> String {
+        format!("readonly {} -> {}", old.readonly, self.readonly)
+    }
+}
+
+/// Position in a tailed file, right after the last complete line read
+#[derive(Clone, Copy, Default)]
+struct Tail {
+    offset: u64,
+    lines: usize
+}
+
+impl Tail {
+    /// Position at the end of the file's last complete line
+    fn end_of(path: &Path) -> Tail {
+        let mut tail = Tail::default();
+        let mut file = match fs::File::open(path) {
+            Ok(t) => t,
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "failed to open, tailing from the start");
+                return tail
+            }
+        };
+
+        let mut chunk = [0u8; 64 * 1024];
+        let mut read: u64 = 0;
+        loop {
+            let len = match file.read(&mut chunk) {
+                Ok(0) | Err(_) => break,
+                Ok(len) => len
+            };
+
+            for (index, byte) in chunk[..len].iter().enumerate() {
+                if *byte == b'\n' {
+                    tail.lines += 1;
+                    tail.offset = read + index as u64 + 1;
+                }
+            }
+            read += len as u64;
+        }
+
+        tail
+    }
+}
+
+impl Default for Node {
+    fn default() -> Self {
+        Node::new()
+    }
+}
+
+impl Node {
+    pub fn new() -> Self {
+        Self {
+            kind: NodeType::Folder,
+            path: PathBuf::new(),
+            name: "root".to_string(),
+            elapsed: None,
+            size: None,
+            hash: None,
+            children: Vec::new(),
+            skipped: HashSet::new(),
+            content: None,
+            depth: 0,
+            tail: None,
+            access: None,
+            binary: false,
+            link: false,
+            recent: VecDeque::new()
+        }
+    }
+    
+    pub fn fill(&mut self, path: &Path) {
+        self.fill_tracked(path, 0, &mut HashSet::new(), CONFIG.load().lazy_content);
+    }
+
+    /// Fill the node, keeping track of the canonical folders already walked
+    /// so that following symlinks can't recurse forever. Lazy fills leave the
+    /// contents of files unread
+    fn fill_tracked(&mut self, path: &Path, depth: usize, visited: &mut HashSet<PathBuf>, lazy: bool) {
+        self.path = path.to_path_buf();
+        self.depth = depth;
+        if let Some(name) = path.file_name() {
+            self.name = name.to_string_lossy().to_string();
+        }
+
+        self.kind = {
+            if path.is_dir() { 
+                NodeType::Folder 
+            } else {
+                NodeType::File
+            }
+        };
+
+        match self.kind {
+            NodeType::File => {
+                // A file given as a root is watched whatever its name
+                if depth > 0 && !path.file_name().is_some_and(|name| TARGETS.get().is_match(name)) {
+                    debug!(path = %path.display(), "skipped, not a target");
+
+                    // Indicator that the file type is invalid
+                    self.path = "...".into();
+                    return
+                }
+
+                (self.elapsed, self.size) = match path.metadata() {
+                    Ok(t) => match Node::modified_millis(path, &t) {
+                        Some(elapsed) if CONFIG.load().watch_metadata => {
+                            self.access = Some(Access::of(&t));
+                            (Some(elapsed), Some(t.len()))
+                        },
+                        Some(elapsed) => (Some(elapsed), Some(t.len())),
+                        None => {
+                            // Without a modification time changes can't be told apart
+                            self.path = "...".into();
+                            return
+                        }
+                    },
+                    Err(e) => {
+                        warn!(path = %path.display(), error = %e, "failed to read metadata");
+                        (None, None)
+                    }
+                };
+
+                // Tailed files are read from where they end now on
+                if is_tailed(path) {
+                    self.tail = Some(Tail::end_of(path));
+                    return
+                }
+
+                if lazy || is_diff_suppressed(path) {
+                    return
+                }
+                
+                match self.read() {
+                    Ok(content) => self.content = Some(content),
+                    Err(FileError::Binary { hash }) => {
+                        self.binary = true;
+                        self.hash = Some(hash);
+                    },
+                    Err(e) => debug!(path = %path.display(), error = %e, "contents not kept")
+                }
+                if CONFIG.load().use_content_hash && !self.binary {
+                    self.hash = self.content.as_deref().map(Node::hash);
+                }
+            },
+            NodeType::Folder => {
+                self.elapsed = None;
+
+                if self.at_depth_limit() {
+                    return
+                }
+
+                if CONFIG.load().follow_symlinks {
+                    if !path.canonicalize().is_ok_and(|canonical| visited.insert(canonical)) {
+                        return
+                    }
+                } else if path.symlink_metadata().is_ok_and(|m| m.file_type().is_symlink()) {
+                    // Linked folders are left as leaves instead of being descended
+                    self.link = true;
+                    return
+                }
+
+                let entries: Vec<fs::DirEntry> = match path.read_dir() {
+                    Ok(t) => t,
+                    _ => return
+                }.filter_map(|res| res.map_err(|e| {
+                    warn!(path = %path.display(), error = %e, "failed to read folder entry");
+                }).ok()).collect();
+
+                let depth = self.depth + 1;
+                let results: Vec<Result<Node, Skip>> = if CONFIG.load().parallel_scan && entries.len() >= PARALLEL_SCAN_MIN_ENTRIES {
+                    // Every branch starts from the folders walked so far, so the
+                    // tree doesn't depend on which thread gets somewhere first
+                    let branches: Vec<(Result<Node, Skip>, HashSet<PathBuf>)> = SCAN_POOL.install(|| {
+                        entries.par_iter().map(|entry| {
+                            let mut branch = visited.clone();
+                            (Node::build_child(entry, depth, &mut branch, lazy), branch)
+                        }).collect()
+                    });
+
+                    branches.into_iter().map(|(result, branch)| {
+                        visited.extend(branch);
+                        result
+                    }).collect()
+                } else {
+                    entries.iter().map(|entry| Node::build_child(entry, depth, visited, lazy)).collect()
+                };
+
+                for (entry, result) in entries.iter().zip(results) {
+                    if let Some(child) = self.adopt(entry, result) {
+                        self.children.push(child);
+                    }
+                }
+            }
+        };
+    }
+
+    /// Build the node for one of this folder's entries, unless it is ignored,
+    /// not a target or a folder without anything to watch
+    fn child_from(&mut self, entry: &fs::DirEntry, visited: &mut HashSet<PathBuf>) -> Option<Node> {
+        let result = Node::build_child(entry, self.depth + 1, visited, false);
+        self.adopt(entry, result)
+    }
+
+    /// Keep the child that was built for an entry. Ignored entries and the ones
+    /// that aren't targets are remembered as skipped so later polls don't look
+    /// at them again, while empty folders are checked again in case something
+    /// appears in them
+    fn adopt(&mut self, entry: &fs::DirEntry, result: Result<Node, Skip>) -> Option<Node> {
+        match result {
+            Ok(child) => Some(child),
+            Err(Skip::Permanently) => {
+                self.skipped.insert(entry.path());
+                None
+            },
+            Err(Skip::ForNow) => None
+        }
+    }
+
+    /// Build the node for a directory entry at the given depth
+    fn build_child(entry: &fs::DirEntry, depth: usize, visited: &mut HashSet<PathBuf>, lazy: bool) -> Result<Node, Skip> {
+        let is_dir = entry.file_type().is_ok_and(|t| t.is_dir());
+
+        // Ignored directories are pruned along with their whole subtree
+        if is_ignored(&entry.path(), is_dir, false) {
+            debug!(path = %entry.path().display(), "skipped, ignored");
+            return Err(Skip::Permanently);
+        }
+
+        if !in_scope(&entry.path(), is_dir) {
+            debug!(path = %entry.path().display(), "skipped, outside of the watched folders");
+            return Err(Skip::Permanently);
+        }
+
+        if !CONFIG.load().watch_hidden && is_hidden(&entry.path()) {
+            debug!(path = %entry.path().display(), "skipped, hidden");
+            return Err(Skip::Permanently);
+        }
+
+        let mut child: Node = Node::new();
+        child.fill_tracked(&entry.path(), depth, visited, lazy);
+
+        if child.path == Path::new("...") {
+            return Err(Skip::Permanently);
+        }
+
+        if child.is_prunable() {
+            return Err(Skip::ForNow);
+        }
+
+        Ok(child)
+    }
+
+    /// Whether the node is a folder without anything to watch, left out of
+    /// the tree unless `include_empty_dirs`. Folders past the depth limit
+    /// and linked ones are kept as leaves, even though they look empty from here
+    fn is_prunable(&self) -> bool {
+        matches!(self.kind, NodeType::Folder) && self.children.is_empty() && !self.link
+            && !self.at_depth_limit() && !CONFIG.load().include_empty_dirs
+    }
+
+    /// Whether the node is as deep as folders are descended
+    fn at_depth_limit(&self) -> bool {
+        CONFIG.load().max_depth.is_some_and(|max| self.depth >= max)
+    }
+
+    pub fn display(&self, prev: &str) {
+        // What's left of the line once the modification time is written after it
+        let width = TREE_WIDTH.get().saturating_sub(TREE_TIME_WIDTH).max(TREE_MIN_NAME_WIDTH);
+
+        // Names too long for the column are cut off, keeping a few dots after them
+        let prefix = format!("{}└── ", prev);
+        let room = width.saturating_sub(prefix.chars().count() + 4);
+        let name_column = if self.name.chars().count() > room {
+            format!("{}{}...", prefix, self.name.chars().take(room).collect::<String>())
+        } else {
+            format!("{}{}", prefix, self.name)
+        };
+
+        println!("{:.<width$} Last Modified: -{} millis", name_column, self.elapsed.unwrap_or(u128::MAX), width=width);
+
+        for child in &self.children {
+            child.display(&format!("{}│  ", prev));
+        }
+    }
+
+    /// Read the contents line by line, never holding more than the size cap
+    fn read(&self) -> core::result::Result<String, FileError> {
+        let file: fs::File = fs::File::open(&self.path).map_err(FileError::Open)?;
+        let metadata: fs::Metadata = file.metadata().map_err(FileError::Metadata)?;
+
+        if metadata.len() > CONFIG.load().max_file_size_bytes {
+            debug!(path = %self.path.display(), size = metadata.len(), "skipped reading, above the size cap");
+            return Err(FileError::TooLarge)
+        }
+
+        // The file may have grown since, so reading stops right past the cap
+        // rather than taking in whatever it holds now
+        let limit = CONFIG.load().max_file_size_bytes;
+        let mut reader = std::io::BufReader::with_capacity(CONFIG.load().read_buffer_bytes.max(1), file.take(limit + 1));
+
+        let mut content = String::new();
+        let mut line: Vec<u8> = Vec::new();
+        let mut hasher = Xxh3::new();
+        let mut total: usize = 0;
+
+        loop {
+            line.clear();
+            match reader.read_until(b'\n', &mut line) {
+                Ok(0) => return Ok(content),
+                Ok(_) => {},
+                Err(e) => return Err(FileError::Read(e))
+            }
+
+            hasher.update(&line);
+            let sniffed = &line[..line.len().min(BINARY_SNIFF_BYTES.saturating_sub(total))];
+            total += line.len();
+            if total as u64 > limit {
+                debug!(path = %self.path.display(), "stopped reading, grew above the size cap");
+                return Err(FileError::TooLarge)
+            }
+
+            let text = if sniffed.contains(&0) {
+                debug!(path = %self.path.display(), "not diffing, contents have null bytes");
+                None
+            } else {
+                std::str::from_utf8(&line).map_err(|_| {
+                    debug!(path = %self.path.display(), "not diffing, contents aren't valid UTF-8");
+                }).ok()
+            };
+
+            match text {
+                Some(text) => content.push_str(text),
+                None => {
+                    // Only the digest of the rest is needed, so it isn't kept
+                    let mut rest = [0u8; 8192];
+                    loop {
+                        match reader.read(&mut rest) {
+                            Ok(0) => break,
+                            Ok(read) => {
+                                hasher.update(&rest[..read]);
+                                total += read;
+                            },
+                            Err(e) => return Err(FileError::Read(e))
+                        }
+                    }
+
+                    if total as u64 > limit {
+                        return Err(FileError::TooLarge)
+                    }
+                    return Err(FileError::Binary { hash: format!("{:016x}", hasher.digest()) })
+                }
+            }
+        }
+    }
+
+    /// Hex encoded xxh3 digest of the file contents
+    fn hash(content: impl AsRef<[u8]>) -> String {
+        format!("{:016x}", xxh3_64(content.as_ref()))
+    }
+
+    /// Modification time in millis since the epoch, `None` when the platform
+    /// can't provide it or it lies before the epoch
+    fn modified_millis(path: &Path, metadata: &fs::Metadata) -> Option<u128> {
+        let since_epoch = metadata.modified()
+            .map_err(|e| e.to_string())
+            .and_then(|t| t.duration_since(UNIX_EPOCH).map_err(|e| e.to_string()));
+
+        match since_epoch {
+            Ok(t) => Some(t.as_millis()),
+            Err(e) => {
+                warn!(path = %path.display(), error = %e, "failed to read modification time");
+                None
+            }
+        }
+    }
+
+    /// Check the subtree for changes, returns false once the node is gone from the disk
+    pub fn poll(&mut self, buffer: &mut Vec<Notification>) -> bool {
+        let (elapsed, size): (Option<u128>, Option<u64>) = match self.path.metadata() {
+            Ok(t) if self.access.is_some() && matches!(self.kind, NodeType::File) => {
+                self.notice_access(Access::of(&t), buffer);
+                match Node::modified_millis(&self.path, &t) {
+                    Some(elapsed) => (Some(elapsed), Some(t.len())),
+                    None => return true
+                }
+            },
+            Ok(t) => match Node::modified_millis(&self.path, &t) {
+                Some(elapsed) => (Some(elapsed), Some(t.len())),
+                // Keep the file as it was until its metadata makes sense again
+                None if matches!(self.kind, NodeType::File) => return true,
+                None => (None, Some(t.len()))
+            },
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+                self.notify_removal(buffer);
+                return false
+            },
+            Err(e) => {
+                warn!(path = %self.path.display(), error = %e, "failed to read metadata");
+                (None, None)
+            }
+        };
+
+        // With hashing enabled an unchanged size and mtime is taken as proof
+        // that the contents are untouched, so the file isn't re-read at all
+        let touched = self.elapsed != elapsed || (CONFIG.load().use_content_hash && self.size != size);
+
+        self.elapsed = elapsed;
+        self.size = size;
+
+        if matches!(self.kind, NodeType::File) && touched && is_diff_suppressed(&self.path) {
+            let mut notif = Notification::new(&self.path, NotificationKind::Modified);
+            notif.size = self.size;
+            notif.diff_suppressed = true;
+            buffer.push(notif);
+        } else if matches!(self.kind, NodeType::File) && touched && self.tail.is_some() {
+            self.read_appended(buffer);
+        } else if matches!(self.kind, NodeType::File) && touched {
+            // Files above the size cap are never read, so a new size or mtime is
+            // reported as a change without a diff. Binary files can't be diffed
+            // line by line either, but their digest tells whether they changed
+            match self.read() {
+                Err(FileError::TooLarge) => {
+                    self.content = None;
+                    self.hash = None;
+                    self.binary = false;
+                    let mut notif = Notification::new(&self.path, NotificationKind::Modified);
+                    notif.size = self.size;
+                    notif.too_large = true;
+                    buffer.push(notif);
+                },
+                Err(FileError::Binary { hash }) => {
+                    self.content = None;
+                    self.binary = true;
+                    let changed = !CONFIG.load().use_content_hash || self.hash.as_ref() != Some(&hash);
+                    self.hash = Some(hash);
+
+                    if changed {
+                        let mut notif = Notification::new(&self.path, NotificationKind::Modified);
+                        notif.size = self.size;
+                        notif.binary = true;
+                        buffer.push(notif);
+                    }
+                },
+                read => {
+                    self.binary = false;
+                    self.notice(read.ok(), buffer);
+                }
+            }
+        }
+
+        let had_children = !self.children.is_empty();
+        self.children.retain_mut(|child| child.poll(buffer));
+
+        if matches!(self.kind, NodeType::Folder) {
+            self.discover(buffer);
+            if had_children && self.children.is_empty() {
+                buffer.push(Notification::new(&self.path, NotificationKind::FolderEmptied));
+            }
+        }
+
+        true
+    }
+
+    /// Queue a notification when the permissions or the owner of the file changed
+    fn notice_access(&mut self, access: Access, buffer: &mut Vec<Notification>) {
+        if let Some(old) = self.access.replace(access).filter(|old| *old != access) {
+            buffer.push(Notification::new(&self.path, NotificationKind::Metadata { change: access.changes_from(&old) }));
+        }
+    }
+
+    /// Queue the complete lines appended to a tailed file since it was last
+    /// read, starting over from the top when the file got shorter
+    fn read_appended(&mut self, buffer: &mut Vec<Notification>) {
+        let mut tail = self.tail.unwrap_or_default();
+        if self.size.is_some_and(|size| size < tail.offset) {
+            debug!(path = %self.path.display(), "truncated, reading from the start");
+            tail = Tail::default();
+        }
+
+        // Reading stops right past the size cap, however much was appended
+        let limit = CONFIG.load().max_file_size_bytes;
+        let mut appended: Vec<u8> = Vec::new();
+        let result = fs::File::open(&self.path).and_then(|mut file| {
+            file.seek(SeekFrom::Start(tail.offset))?;
+            file.take(limit + 1).read_to_end(&mut appended)
+        });
+        if let Err(e) = result {
+            warn!(path = %self.path.display(), error = %e, "failed to read the appended lines");
+            return;
+        }
+
+        // Too much to hold at once, so it's only reported as changed and
+        // tailing carries on from the end
+        if appended.len() as u64 > limit {
+            debug!(path = %self.path.display(), "not reading the appended lines, above the size cap");
+            self.tail = Some(Tail::end_of(&self.path));
+            let mut notif = Notification::new(&self.path, NotificationKind::Modified);
+            notif.size = self.size;
+            notif.too_large = true;
+            buffer.push(notif);
+            return;
+        }
+
+        // A line still being written is left for the next read
+        let complete = appended.iter().rposition(|byte| *byte == b'\n').map_or(0, |i| i + 1);
+        if complete == 0 {
+            self.tail = Some(tail);
+            return;
+        }
+
+        let mut notif = Notification::new(&self.path, NotificationKind::Modified);
+        notif.size = self.size;
+        notif.first_line = tail.lines;
+        notif.diff = String::from_utf8_lossy(&appended[..complete]).lines()
+            .map(|line| diff::Result::Right(line.to_string()))
+            .collect();
+
+        self.tail = Some(Tail { offset: tail.offset + complete as u64, lines: tail.lines + notif.diff.len() });
+        if notif.is_significant() {
+            buffer.push(notif);
+        }
+    }
+
+    /// Queue a notification for freshly read contents, unless hashing shows
+    /// that they are the same as before
+    fn notice(&mut self, option_new_lines: Option<String>, buffer: &mut Vec<Notification>) {
+        if !CONFIG.load().use_content_hash || self.rehash(option_new_lines.as_deref()) {
+            self.notify_change(option_new_lines, buffer);
+        }
+    }
+
+    /// Keep the digest of freshly read contents, telling whether it differs
+    /// from the last one
+    fn rehash(&mut self, new_lines: Option<&str>) -> bool {
+        let hash = new_lines.map(Node::hash);
+        let changed = self.hash != hash;
+        self.hash = hash;
+        changed
+    }
+
+    /// Pick up the entries of a folder that appeared since it was last read
+    fn discover(&mut self, buffer: &mut Vec<Notification>) {
+        if self.at_depth_limit() || self.link {
+            return;
+        }
+
+        let entries = match self.path.read_dir() {
+            Ok(t) => t,
+            _ => return
+        };
+
+        let known: HashSet<PathBuf> = self.children.iter().map(|child| child.path.clone()).collect();
+        let mut seen: HashSet<PathBuf> = HashSet::new();
+
+        // Links back into the folders above have to stay leaves, or every poll
+        // would discover one more level of the cycle
+        let mut visited: HashSet<PathBuf> = if CONFIG.load().follow_symlinks {
+            self.path.ancestors().filter_map(|path| path.canonicalize().ok()).collect()
+        } else {
+            HashSet::new()
+        };
+
+        // In path order, so the files taken in under `max_watched_files` are
+        // the first ones like on startup
+        let mut entries: Vec<fs::DirEntry> = entries.flatten().collect();
+        entries.sort_by_key(fs::DirEntry::path);
+
+        for entry in entries {
+            let path = entry.path();
+            if known.contains(&path) || self.skipped.contains(&path) {
+                seen.insert(path);
+                continue;
+            }
+            seen.insert(path);
+
+            // Nothing new would be taken in, so nothing is read for it
+            if at_file_cap() {
+                continue;
+            }
+
+            if let Some(mut child) = self.child_from(&entry, &mut visited) {
+                if admit(&mut child, buffer) {
+                    self.children.push(child);
+                }
+            }
+        }
+
+        // Forget about the skipped entries that are gone
+        self.skipped.retain(|path| seen.contains(path));
+    }
+
+    /// List this subtree in the order it's displayed
+    fn lines(&self, lines: &mut Vec<TreeLine>) {
+        lines.push(TreeLine {
+            depth: self.depth,
+            name: self.name.clone(),
+            modified: self.elapsed.map(|ms| UNIX_EPOCH + Duration::from_millis(ms as u64))
+        });
+
+        for child in &self.children {
+            child.lines(lines);
+        }
+    }
+
+    /// Digest of the contents as last read, text or binary
+    fn content_hash(&self) -> Option<String> {
+        match &self.content {
+            Some(content) => Some(Node::hash(content)),
+            None if self.binary => self.hash.clone(),
+            None => None
+        }
+    }
+
+    /// Whether the file was left unread for being above the size cap
+    fn is_too_large(&self) -> bool {
+        self.tail.is_none() && self.size.is_some_and(|size| size > CONFIG.load().max_file_size_bytes)
+    }
+
+    /// Number of files in this subtree
+    fn count_files(&self) -> usize {
+        let own = usize::from(matches!(self.kind, NodeType::File));
+        own + self.children.iter().map(Node::count_files).sum::<usize>()
+    }
+
+    /// Collect the paths of the files in this subtree
+    fn file_paths(&self, paths: &mut Vec<PathBuf>) {
+        if matches!(self.kind, NodeType::File) {
+            paths.push(self.path.clone());
+        }
+
+        for child in &self.children {
+            child.file_paths(paths);
+        }
+    }
+
+    /// Take the given files out of this subtree, along with the folders
+    /// left without anything to watch
+    fn drop_files(&mut self, files: &HashSet<PathBuf>) {
+        self.children.retain_mut(|child| match child.kind {
+            NodeType::File => !files.contains(&child.path),
+            NodeType::Folder => {
+                let had_children = !child.children.is_empty();
+                child.drop_files(files);
+                !(had_children && child.is_prunable())
+            }
+        });
+    }
+
+    /// Note down the state of every file in this subtree, along with the
+    /// contents that aren't too large to be saved when asked to
+    fn record(&self, files: &mut BTreeMap<PathBuf, FileState>, contents: bool) {
+        if matches!(self.kind, NodeType::File) {
+            files.insert(self.path.clone(), FileState {
+                mtime: self.elapsed,
+                hash: self.content_hash(),
+                size: self.size,
+                content: self.content.clone().filter(|content| contents && content.len() <= STATE_CONTENT_MAX_BYTES)
+            });
+        }
+
+        for child in &self.children {
+            child.record(files, contents);
+        }
+    }
+
+    /// Find the node for the given path within this subtree
+    fn find_mut(&mut self, path: &Path) -> Option<&mut Node> {
+        if self.path == path {
+            return Some(self);
+        }
+
+        self.children.iter_mut()
+            .filter(|child| path.starts_with(&child.path))
+            .find_map(|child| child.find_mut(path))
+    }
+
+    /// Attach a node below this one, creating the folders in between
+    fn insert(&mut self, node: Node) {
+        if let Some(child) = self.children.iter_mut()
+            .find(|child| matches!(child.kind, NodeType::Folder) && node.path.starts_with(&child.path)) {
+            return child.insert(node);
+        }
+
+        let component = match node.path.strip_prefix(&self.path).ok().and_then(|rest| rest.components().next()) {
+            Some(t) => t,
+            _ => return
+        };
+
+        let folder_path = self.path.join(component);
+        if folder_path == node.path {
+            self.children.push(node);
+            return;
+        }
+
+        let mut folder = Node::new();
+        folder.name = component.as_os_str().to_string_lossy().to_string();
+        folder.path = folder_path;
+        folder.depth = self.depth + 1;
+        folder.insert(node);
+        self.children.push(folder);
+    }
+
+    /// Detach the node for the given path from this subtree
+    fn remove(&mut self, path: &Path) -> Option<Node> {
+        if let Some(index) = self.children.iter().position(|child| child.path == path) {
+            return Some(self.children.remove(index));
+        }
+
+        self.children.iter_mut()
+            .filter(|child| path.starts_with(&child.path))
+            .find_map(|child| child.remove(path))
+    }
+
+    /// Queue a notification per file in this subtree with all of its lines added
+    fn notify_creation(&self, buffer: &mut Vec<Notification>) {
+        if matches!(self.kind, NodeType::File) {
+            let mut notif = Notification::new(&self.path, NotificationKind::Created);
+            notif.size = self.size;
+            notif.too_large = self.is_too_large();
+            notif.diff_suppressed = is_diff_suppressed(&self.path);
+            notif.binary = self.binary;
+            notif.hash = self.content_hash();
+            if let Some(content) = &self.content {
+                notif.diff = content.lines().map(|l| diff::Result::Right(l.to_string())).collect();
+                if CONFIG.load().include_full_content {
+                    notif.content = Some(content.clone());
+                }
+            }
+            buffer.push(notif);
+        }
+
+        for child in &self.children {
+            child.notify_creation(buffer);
+        }
+    }
+
+    /// Queue a notification per file in this subtree with all of its lines
+    /// removed, and one per folder once its files are gone
+    fn notify_removal(&self, buffer: &mut Vec<Notification>) {
+        if matches!(self.kind, NodeType::File) {
+            let mut notif = Notification::new(&self.path, NotificationKind::Deleted);
+            notif.size = Some(0);
+            notif.too_large = self.is_too_large();
+            notif.diff_suppressed = is_diff_suppressed(&self.path);
+            notif.binary = self.binary;
+            notif.hash = self.content_hash();
+            if let Some(content) = &self.content {
+                notif.diff = content.lines().map(|l| diff::Result::Left(l.to_string())).collect();
+            }
+            buffer.push(notif);
+        }
+
+        for child in &self.children {
+            child.notify_removal(buffer);
+        }
+
+        if matches!(self.kind, NodeType::Folder) {
+            buffer.push(Notification::new(&self.path, NotificationKind::FolderDeleted));
+        }
+    }
+
+    /// Remember the contents being left, and tell whether the new ones are
+    /// among the ones left within `dedup_window_secs`
+    fn seen_recently(recent: &mut VecDeque<(u64, Instant)>, old: &str, new: &str) -> bool {
+        let window = match CONFIG.load().dedup_window_secs {
+            Some(secs) => Duration::from_secs(secs),
+            None => return false
+        };
+
+        let now = Instant::now();
+        recent.retain(|(_, left)| now.duration_since(*left) <= window);
+        recent.push_back((xxh3_64(old.as_bytes()), now));
+        while recent.len() > CONFIG.load().dedup_history.max(1) {
+            recent.pop_front();
+        }
+
+        let hash = xxh3_64(new.as_bytes());
+        recent.iter().any(|(seen, _)| *seen == hash)
+    }
+
+    /// Diff the stored contents against the new ones and queue a notification
+    fn notify_change(&mut self, option_new_lines: Option<String>, buffer: &mut Vec<Notification>) {
+        let mut notifs = Notification::new(&self.path, NotificationKind::Modified);
+        notifs.size = self.size;
+
+        // The new contents are kept and diffed from there, without copying them
+        let mut option_old_lines = std::mem::replace(&mut self.content, option_new_lines);
+
+        // What was left is remembered whatever the diff is taken against
+        if let (Some(old_lines), Some(new_lines)) = (&option_old_lines, self.content.as_deref()) {
+            if Node::seen_recently(&mut self.recent, old_lines, new_lines) {
+                debug!(path = %self.path.display(), "not reported, back to contents it recently had");
+                return;
+            }
+        }
+
+        // Untracked files keep being diffed against their previous contents
+        if CONFIG.load().diff_against == "git_head" {
+            if let Some(committed) = committed_contents(&self.path) {
+                option_old_lines = Some(committed);
+            }
+        }
+
+        if let (Some(old_lines), Some(new_lines)) = (option_old_lines, self.content.as_deref()) {
+            // Both versions and their diff would have to be held at once, so
+            // very large ones are only reported as changed
+            let bytes = old_lines.len() + new_lines.len();
+            if CONFIG.load().max_diff_bytes > 0 && bytes > CONFIG.load().max_diff_bytes {
+                debug!(path = %self.path.display(), bytes, "not diffing, above max_diff_bytes");
+                notifs.too_large = true;
+                notifs.size_delta = Some(new_lines.len() as i64 - old_lines.len() as i64);
+                buffer.push(notifs);
+                return;
+            }
+
+            if CONFIG.load().semantic_diff {
+                notifs.keys = key_changes(&self.path, &old_lines, new_lines);
+            }
+
+            let old_lines: Vec<&str> = old_lines.lines().collect();
+            let new_lines: Vec<&str> = new_lines.lines().collect();
+
+            notifs.diff = diff_lines(&old_lines, &new_lines);
+            if notifs.is_significant() {
+                if CONFIG.load().include_full_content {
+                    notifs.content = self.content.clone();
+                }
+                buffer.push(notifs);
+            }
+        } else if self.content.is_some() {
+            // The previous contents weren't available (e.g. the file used to
+            // be above the size cap), so there's nothing to diff against
+            if CONFIG.load().include_full_content {
+                notifs.content = self.content.clone();
+            }
+            buffer.push(notifs);
+        }
+    }
+}
+
+/// Diff two versions of a file line by line with the configured algorithm.
+/// Unchanged lines only have their text on the left, the right is left empty
+/// rather than holding a second copy
+fn diff_lines(old_lines: &[&str], new_lines: &[&str]) -> Vec<diff::Result<String>> {
+    let algorithm = match CONFIG.load().diff_algorithm.as_str() {
+        "myers" => similar::Algorithm::Myers,
+        "patience" => similar::Algorithm::Patience,
+        _ => {
+            return diff::slice(old_lines, new_lines).iter().map(|change| match change {
+                diff::Result::Left(l) => diff::Result::Left(l.to_string()),
+                diff::Result::Both(l, _) => diff::Result::Both(l.to_string(), String::new()),
+                diff::Result::Right(r) => diff::Result::Right(r.to_string())
+            }).collect()
+        }
+    };
+
+    similar::TextDiff::configure()
+        .algorithm(algorithm)
+        .diff_slices(old_lines, new_lines)
+        .iter_all_changes()
+        .map(|change| match change.tag() {
+            similar::ChangeTag::Delete => diff::Result::Left(change.value().to_string()),
+            similar::ChangeTag::Insert => diff::Result::Right(change.value().to_string()),
+            similar::ChangeTag::Equal => diff::Result::Both(change.value().to_string(), String::new())
+        })
+        .collect()
+}
+
+/// A key of a structured file that was added, removed or changed, either
+/// side is missing when the key is
+pub struct KeyChange {
+    /// Where the key is, like `a.b.c` or `items[2]`
+    pub path: String,
+    pub old: Option<serde_json::Value>,
+    pub new: Option<serde_json::Value>
+}
+
+impl KeyChange {
+    /// The change on one line, marked like the lines of a diff
+    fn line(&self) -> String {
+        match (&self.old, &self.new) {
+            (Some(old), Some(new)) => format!("~ {}: {} -> {}", self.path, old, new),
+            (Some(old), None) => format!("- {}: {}", self.path, old),
+            (None, Some(new)) => format!("+ {}: {}", self.path, new),
+            (None, None) => format!("  {}", self.path)
+        }
+    }
+}
+
+/// Parse a JSON, TOML or YAML file's contents, `None` for other files or
+/// contents that don't parse
+fn parse_structured(path: &Path, contents: &str) -> Option<serde_json::Value> {
+    let extension = path.extension()?.to_str()?.to_ascii_lowercase();
+    match extension.as_str() {
+        "json" => serde_json::from_str(contents).ok(),
+        "toml" => toml::from_str::<toml::Value>(contents).ok().and_then(|value| serde_json::to_value(value).ok()),
+        "yaml" | "yml" => serde_yaml::from_str(contents).ok(),
+        _ => None
+    }
+}
+
+/// The keys that differ between two versions of a structured file, `None`
+/// when either doesn't parse so the line diff is shown instead
+fn key_changes(path: &Path, old: &str, new: &str) -> Option<Vec<KeyChange>> {
+    let old = parse_structured(path, old)?;
+    let new = parse_structured(path, new)?;
+
+    let mut changes = Vec::new();
+    compare_values(String::new(), Some(&old), Some(&new), &mut changes);
+    Some(changes)
+}
+
+/// Walk two values side by side, recording the leaves that differ. Arrays are
+/// compared index by index
+fn compare_values(path: String, old: Option<&serde_json::Value>, new: Option<&serde_json::Value>, changes: &mut Vec<KeyChange>) {
+    use serde_json::Value;
+
+    let key = |name: &str| if path.is_empty() { name.to_string() } else { format!("{}.{}", path, name) };
+    match (old, new) {
+        (Some(Value::Object(old)), Some(Value::Object(new))) => {
+            let names: std::collections::BTreeSet<&String> = old.keys().chain(new.keys()).collect();
+            for name in names {
+                compare_values(key(name), old.get(name), new.get(name), changes);
+            }
+        },
+        (Some(Value::Array(old)), Some(Value::Array(new))) => {
+            for index in 0..old.len().max(new.len()) {
+                compare_values(format!("{}[{}]", path, index), old.get(index), new.get(index), changes);
+            }
+        },
+        (old, new) if old != new => changes.push(KeyChange {
+            path: if path.is_empty() { "(root)".to_string() } else { path },
+            old: old.cloned(),
+            new: new.cloned()
+        }),
+        _ => {}
+    }
+}
+
+#[derive(Clone)]
+pub enum NotificationKind {
+    Modified,
+    Created,
+    Deleted,
+    /// A file that moved without changing, the notification's path is `to`
+    Renamed { from: PathBuf, to: PathBuf },
+    /// The watcher started watching the files under the notification's path
+    Started { files: Vec<PathBuf> },
+    /// The permissions or the owner of the file changed, as described
+    Metadata { change: String },
+    /// A watched folder was removed, after its files were
+    FolderDeleted,
+    /// A watched folder lost the last of its watched files and folders
+    FolderEmptied
+}
+
+impl NotificationKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            NotificationKind::Modified => "modified",
+            NotificationKind::Created => "created",
+            NotificationKind::Deleted => "deleted",
+            NotificationKind::Renamed { .. } => "renamed",
+            NotificationKind::Started { .. } => "started",
+            NotificationKind::Metadata { .. } => "metadata",
+            NotificationKind::FolderDeleted => "folder_deleted",
+            NotificationKind::FolderEmptied => "folder_emptied"
+        }
+    }
+}
+
+/// How a path is written out: relative to the root with `relative_paths`,
+/// except for the root itself. With several roots the same relative path
+/// could be under any of them, so they're all written out absolute
+fn shown(path: &Path) -> String {
+    let config = CONFIG.load();
+    shown_under(path, &config.roots, config.relative_paths)
+}
+
+/// How a path under the given roots is written out
+fn shown_under(path: &Path, roots: &[PathBuf], relative: bool) -> String {
+    if let ([root], true) = (roots, relative) {
+        if let Some(rest) = path.strip_prefix(root).ok().filter(|rest| !rest.as_os_str().is_empty()) {
+            return rest.to_string_lossy().into_owned();
+        }
+    }
+
+    path.to_string_lossy().into_owned()
+}
+
+pub struct Notification {
+    /// Order the notification was emitted in, counting from zero again on
+    /// every run. Gaps downstream mean notifications got lost
+    pub seq: u64,
+    pub time: SystemTime, 
+    pub kind: NotificationKind,
+    pub path: PathBuf,
+    /// The lines of the diff, unchanged ones only having their text on the left
+    pub diff: Vec<diff::Result<String>>,
+    /// Length of the file's new contents when known, zero once it's gone
+    pub size: Option<u64>,
+    /// The file is above the size cap, or both versions above
+    /// `max_diff_bytes`, so there's no diff for it
+    pub too_large: bool,
+    /// Bytes the file grew by, or shrank by when negative, when it changed
+    /// without a diff for being too large
+    pub size_delta: Option<i64>,
+    /// The file is binary, so there's no diff for it
+    pub binary: bool,
+    /// The file is one of `no_diff_targets`, so its contents aren't sent
+    pub diff_suppressed: bool,
+    /// The keys that changed, for structured files with `semantic_diff`
+    pub keys: Option<Vec<KeyChange>>,
+    /// The whole new contents of the file, with `include_full_content`
+    pub content: Option<String>,
+    /// Digest of the created or deleted contents, to tell renames apart
+    hash: Option<String>,
+    /// Lines of the file before the diff, when it doesn't start at the top
+    first_line: usize
+}
+
+impl Notification {
+    pub fn new(path: &Path, kind: NotificationKind) -> Self {
+        Self {
+            seq: 0,
+            time: SystemTime::now(),
+            kind,
+            path: path.to_path_buf().clone(),
+            diff: Vec::new(),
+            size: None,
+            too_large: false,
+            size_delta: None,
+            binary: false,
+            diff_suppressed: false,
+            keys: None,
+            content: None,
+            hash: None,
+            first_line: 0
+        }
+    }
+
+    /// The time, kind and path(s) of the notification on one line
+    fn header(&self) -> String {
+        let time = Notification::format_system_time(&self.time);
+        let header = match &self.kind {
+            NotificationKind::Renamed { from, to } => format!("[{}] #{} - {} {} -> {}", time, self.seq, self.kind.as_str(), shown(from), shown(to)),
+            NotificationKind::Started { files } => format!("[{}] #{} - {} {}, watching {} files", time, self.seq, self.kind.as_str(), shown(&self.path), files.len()),
+            NotificationKind::Metadata { change } => format!("[{}] #{} - {} {} ({})", time, self.seq, self.kind.as_str(), shown(&self.path), change),
+            kind => format!("[{}] #{} - {} {}", time, self.seq, kind.as_str(), shown(&self.path))
+        };
+
+        if let (true, Some(delta)) = (self.too_large, self.size_delta) {
+            format!("{} (too large to diff, {:+} bytes)", header, delta)
+        } else if self.too_large {
+            format!("{} (too large to diff)", header)
+        } else if self.binary {
+            format!("{} (binary, not diffed)", header)
+        } else if self.diff_suppressed {
+            format!("{} (diff suppressed)", header)
+        } else {
+            header
+        }
+    }
+
+    fn format_system_time(time: &SystemTime) -> String {
+        let datetime = match time.duration_since(UNIX_EPOCH) {
+            Ok(duration) => {
+                let secs = duration.as_secs() as i64;
+                let nanos = duration.subsec_nanos();
+                DateTime::<Utc>::from_timestamp(secs, nanos).unwrap()
+            },
+            Err(_) => {
+                Utc::now() 
+            }
+        };
+
+        let config = CONFIG.load();
+        let format = config.time_format.as_str();
+        match &*DISPLAY_ZONE.get() {
+            DisplayZone::Local => datetime.with_timezone(&Local).format(format).to_string(),
+            DisplayZone::Utc => datetime.format(format).to_string(),
+            DisplayZone::Named(zone) => datetime.with_timezone(zone).format(format).to_string()
+        }
+    }
+
+    pub fn display(&self) {
+        if CONFIG.load().diff_format == "unified" {
+            println!("{}", self.header());
+
+            for line in self.unified_diff().lines() {
+                let style = match line.chars().next() {
+                    _ if line.starts_with("---") || line.starts_with("+++") => Style::new().bold(),
+                    Some('-') => Style::new().red(),
+                    Some('+') => Style::new().green(),
+                    Some('@') => Style::new().cyan(),
+                    _ => Style::new()
+                };
+                println!("{}", paint(line, style, *COLOR.get()));
+            }
+            return;
+        }
+
+        print!("{}", self.simple(*COLOR.get()));
+    }
+
+    /// Render the header followed by the numbered lines that changed
+    pub fn simple(&self, color: bool) -> String {
+        let mut output = format!("{}\n", self.header());
+
+        if let Some(keys) = &self.keys {
+            for change in keys {
+                let style = match (&change.old, &change.new) {
+                    (Some(_), None) => Style::new().red(),
+                    (None, Some(_)) => Style::new().green(),
+                    _ => Style::new().yellow()
+                };
+                output.push_str(&paint(&change.line(), style, color));
+                output.push('\n');
+            }
+            return Notification::truncate(output, 1, CONFIG.load().max_diff_lines);
+        }
+
+        let text = |change: &diff::Result<String>| match change {
+            diff::Result::Left(l) | diff::Result::Right(l) | diff::Result::Both(l, _) => l.clone()
+        };
+
+        // Shown as `old:new`, with dashes on the side the line doesn't exist in
+        let lines = self.line_numbers();
+        let number = |line: Option<usize>| line.map_or("-----".to_string(), |n| format!("{:0>5}", n));
+        let near = self.near_changes();
+
+        // Walk the diff one block of removed lines followed by added lines at a time,
+        // so that with word diffs enabled each removed line is paired with its replacement
+        let mut index = 0;
+        while index < self.diff.len() {
+            let removed_start = index;
+            while index < self.diff.len() && matches!(self.diff[index], diff::Result::Left(_)) {
+                index += 1;
+            }
+
+            let added_start = index;
+            while index < self.diff.len() && matches!(self.diff[index], diff::Result::Right(_)) {
+                index += 1;
+            }
+
+            let removed = &self.diff[removed_start..added_start];
+            let added = &self.diff[added_start..index];
+
+            if removed.is_empty() && added.is_empty() {
+                // Unchanged lines are only there as the context of a change
+                if near[index] {
+                    let (old, new) = lines[index];
+                    output.push_str(&format!("{}:{}   |  {}\n", number(old), number(new), text(&self.diff[index])));
+                }
+                index += 1;
+                continue;
+            }
+
+            let paired = if CONFIG.load().word_diff { removed.len().min(added.len()) } else { 0 };
+
+            for i in 0..paired {
+                let (old, new) = (lines[removed_start + i].0, lines[added_start + i].1);
+                output.push_str(&format!("{}:{} ~ |  {}\n", number(old), number(new), Notification::word_diff(&text(&removed[i]), &text(&added[i]), color)));
+            }
+
+            for (i, l) in removed.iter().enumerate().skip(paired) {
+                let (old, new) = lines[removed_start + i];
+                output.push_str(&paint(&format!("{}:{} - |  {}", number(old), number(new), text(l)), Style::new().red(), color));
+                output.push('\n');
+            }
+
+            for (i, r) in added.iter().enumerate().skip(paired) {
+                let (old, new) = lines[added_start + i];
+                output.push_str(&paint(&format!("{}:{} + |  {}", number(old), number(new), text(r)), Style::new().green(), color));
+                output.push('\n');
+            }
+        }
+
+        Notification::truncate(output, 1, CONFIG.load().max_diff_lines)
+    }
+
+    /// Which lines of the diff are changes, or unchanged lines within
+    /// `context_lines` of one
+    fn near_changes(&self) -> Vec<bool> {
+        let context = CONFIG.load().context_lines;
+        let mut near = vec![false; self.diff.len()];
+
+        for (index, change) in self.diff.iter().enumerate() {
+            if !matches!(change, diff::Result::Both(_, _)) {
+                let end = (index + context + 1).min(self.diff.len());
+                near[index.saturating_sub(context)..end].fill(true);
+            }
+        }
+        near
+    }
+
+    /// Cut the rendered diff down to `limit` lines below its header lines,
+    /// ending it with a note of how many lines were left out. No limit when zero
+    fn truncate(output: String, header_lines: usize, limit: usize) -> String {
+        let total = output.lines().count().saturating_sub(header_lines);
+        if limit == 0 || total <= limit {
+            return output;
+        }
+
+        let mut kept: String = output.split_inclusive('\n').take(header_lines + limit).collect();
+        kept.push_str(&format!("... (diff truncated, {} more lines)\n", total - limit));
+        kept
+    }
+
+    /// Whether the diff is big enough to be worth reporting, as set by
+    /// `min_changed_lines` and `ignore_whitespace_only`
+    fn is_significant(&self) -> bool {
+        // Reformatting a structured file changes lines but none of its keys
+        if self.keys.as_ref().is_some_and(Vec::is_empty) {
+            return false;
+        }
+
+        let changed = self.diff.iter().filter(|change| !matches!(change, diff::Result::Both(_, _))).count();
+        if changed < CONFIG.load().min_changed_lines {
+            return false;
+        }
+
+        if CONFIG.load().ignore_whitespace_only && changed > 0 {
+            let strip = |left: bool| -> String {
+                self.diff.iter().filter_map(|change| match change {
+                    diff::Result::Left(l) if left => Some(l),
+                    diff::Result::Right(r) if !left => Some(r),
+                    _ => None
+                }).flat_map(|line| line.chars()).filter(|c| !c.is_whitespace()).collect()
+            };
+
+            return strip(true) != strip(false);
+        }
+
+        true
+    }
+
+    /// Line numbers in the old and new file reached before each diff entry,
+    /// followed by the line counts of both files
+    fn positions(&self) -> Vec<(usize, usize)> {
+        let mut positions: Vec<(usize, usize)> = Vec::with_capacity(self.diff.len() + 1);
+        let (mut old_line, mut new_line) = (self.first_line, self.first_line);
+        for change in &self.diff {
+            positions.push((old_line, new_line));
+            match change {
+                diff::Result::Left(_) => old_line += 1,
+                diff::Result::Right(_) => new_line += 1,
+                diff::Result::Both(_, _) => { old_line += 1; new_line += 1; }
+            }
+        }
+        positions.push((old_line, new_line));
+        positions
+    }
+
+    /// The 1-based line numbers of every diff entry in the old and the new
+    /// file, an added line has no old one and a removed line no new one
+    fn line_numbers(&self) -> Vec<(Option<usize>, Option<usize>)> {
+        self.diff.iter().zip(self.positions()).map(|(change, (old, new))| match change {
+            diff::Result::Left(_) => (Some(old + 1), None),
+            diff::Result::Right(_) => (None, Some(new + 1)),
+            diff::Result::Both(_, _) => (Some(old + 1), Some(new + 1))
+        }).collect()
+    }
+
+    /// Split a line into runs of word characters, runs of whitespace and
+    /// single punctuation characters
+    fn tokenize(line: &str) -> Vec<&str> {
+        let class = |c: char| {
+            if c.is_alphanumeric() || c == '_' { 0 } else if c.is_whitespace() { 1 } else { 2 }
+        };
+
+        let mut tokens = Vec::new();
+        let mut start = 0;
+        let mut previous: Option<u8> = None;
+
+        for (index, c) in line.char_indices() {
+            let current = class(c);
+            if previous.is_some_and(|p| p != current || current == 2) {
+                tokens.push(&line[start..index]);
+                start = index;
+            }
+            previous = Some(current);
+        }
+
+        if start < line.len() {
+            tokens.push(&line[start..]);
+        }
+
+        tokens
+    }
+
+    /// Render the changes between two lines inline, marking removed spans as
+    /// `[-...-]` and added spans as `{+...+}`
+    fn word_diff(old: &str, new: &str, color: bool) -> String {
+        let old_tokens = Notification::tokenize(old);
+        let new_tokens = Notification::tokenize(new);
+
+        let mut output = String::new();
+        let mut removed = String::new();
+        let mut added = String::new();
+
+        let flush = |output: &mut String, removed: &mut String, added: &mut String| {
+            if !removed.is_empty() {
+                output.push_str(&paint(&format!("[-{}-]", removed), Style::new().red(), color));
+                removed.clear();
+            }
+            if !added.is_empty() {
+                output.push_str(&paint(&format!("{{+{}+}}", added), Style::new().green(), color));
+                added.clear();
+            }
+        };
+
+        for change in diff::slice(&old_tokens, &new_tokens) {
+            match change {
+                diff::Result::Left(l) => removed.push_str(l),
+                diff::Result::Right(r) => added.push_str(r),
+                diff::Result::Both(l, _) => {
+                    flush(&mut output, &mut removed, &mut added);
+                    output.push_str(l);
+                }
+            }
+        }
+        flush(&mut output, &mut removed, &mut added);
+
+        output
+    }
+
+    /// Render the diff as a unified diff that standard tooling like `patch` accepts
+    pub fn unified_diff(&self) -> String {
+        let path = shown(&self.path);
+        let (old_name, new_name) = match &self.kind {
+            NotificationKind::Created => ("/dev/null".into(), path),
+            NotificationKind::Deleted | NotificationKind::FolderDeleted => (path, "/dev/null".into()),
+            NotificationKind::Modified | NotificationKind::Started { .. } | NotificationKind::Metadata { .. }
+                | NotificationKind::FolderEmptied => (path.clone(), path),
+            NotificationKind::Renamed { from, .. } => (shown(from), path)
+        };
+
+        let mut output = format!("--- {}\n+++ {}\n", old_name, new_name);
+
+        let positions = self.positions();
+
+        // Group the changes into hunks, merging the ones whose context overlaps
+        let context = CONFIG.load().context_lines;
+        let mut hunks: Vec<(usize, usize)> = Vec::new();
+        for (index, change) in self.diff.iter().enumerate() {
+            if matches!(change, diff::Result::Both(_, _)) {
+                continue;
+            }
+
+            let start = index.saturating_sub(context);
+            let end = (index + context + 1).min(self.diff.len());
+            match hunks.last_mut() {
+                Some(last) if start <= last.1 => last.1 = end,
+                _ => hunks.push((start, end))
+            }
+        }
+
+        for (start, end) in hunks {
+            let (old_start, new_start) = positions[start];
+            let old_len = positions[end].0 - old_start;
+            let new_len = positions[end].1 - new_start;
+
+            // Empty ranges point at the line before them, as in GNU diff
+            output.push_str(&format!("@@ -{},{} +{},{} @@\n",
+                if old_len == 0 { old_start } else { old_start + 1 }, old_len,
+                if new_len == 0 { new_start } else { new_start + 1 }, new_len));
+
+            for change in &self.diff[start..end] {
+                match change {
+                    diff::Result::Left(l) => output.push_str(&format!("-{}\n", l)),
+                    diff::Result::Right(r) => output.push_str(&format!("+{}\n", r)),
+                    diff::Result::Both(l, _) => output.push_str(&format!(" {}\n", l))
+                }
+            }
+        }
+
+        Notification::truncate(output, 2, CONFIG.load().max_diff_lines)
+    }
+
+    pub fn json(&self) -> String {
+        serde_json::to_string(&self.value()).unwrap()
+    }
+
+    /// Render the notification with `payload_template`, as JSON when it can't be
+    fn templated(&self) -> String {
+        let template = TEMPLATE.get();
+        let template = match &*template {
+            Some(t) => t,
+            None => return self.json()
+        };
+
+        let simple = self.simple(false);
+        let count = |left: bool| self.diff.iter().filter(|change| match change {
+            diff::Result::Left(_) => left,
+            diff::Result::Right(_) => !left,
+            diff::Result::Both(_, _) => false
+        }).count();
+
+        let context = serde_json::json!({
+            "seq": self.seq,
+            "source": *SOURCE.get(),
+            "time": DateTime::<Utc>::from(self.time).to_rfc3339(),
+            "kind": self.kind.as_str(),
+            "path": shown(&self.path),
+            "added": count(false),
+            "removed": count(true),
+            "diff": simple.split_once('\n').map_or("", |(_, lines)| lines.trim_end())
+        });
+
+        render_template(template, &context).unwrap_or_else(|e| {
+            error!(error = %e, "failed to render the payload template, sending JSON");
+            self.json()
+        })
+    }
+
+    /// The JSON object describing this notification
+    pub fn value(&self) -> serde_json::Value {
+        let datetime: DateTime<Utc> = self.time.into();
+        let rfc_dt = datetime.to_rfc3339();
+
+        #[derive(serde::Serialize, serde::Deserialize)]
+        struct Change {
+            direction: i8,
+            change: String,
+            old_line: Option<usize>,
+            new_line: Option<usize>
+        }
+
+        let mut diff_result: Vec<Change> = Vec::new();
+        let (mut added, mut removed, mut unchanged) = (0, 0, 0);
+
+        self.diff.iter().zip(self.line_numbers()).for_each(|(change, (old_line, new_line))| {
+            match change {
+                diff::Result::Left(l) => {
+                    removed += 1;
+                    diff_result.push(Change{direction: -1, change: l.to_string(), old_line, new_line})
+                },
+                diff::Result::Right(r) => {
+                    added += 1;
+                    diff_result.push(Change{direction: 1, change: r.to_string(), old_line, new_line})
+                },
+                diff::Result::Both(l, _) => {
+                    unchanged += 1;
+                    diff_result.push(Change{direction: 0, change: l.to_string(), old_line, new_line})
+                },
+            }
+        });
+
+        let total_lines = diff_result.len();
+        let truncated = CONFIG.load().max_diff_lines > 0 && total_lines > CONFIG.load().max_diff_lines;
+        if truncated {
+            diff_result.truncate(CONFIG.load().max_diff_lines);
+        }
+
+        let mut value = serde_json::json! ({
+            "seq": self.seq,
+            "source": *SOURCE.get(),
+            "time": rfc_dt,
+            "kind": self.kind.as_str(),
+            "path": shown(&self.path),
+            "added": added,
+            "removed": removed,
+            "unchanged": unchanged,
+            "size_bytes": self.size,
+            "too_large": self.too_large,
+            "size_delta": self.size_delta,
+            "binary": self.binary,
+            "diff_suppressed": self.diff_suppressed,
+            "truncated": truncated,
+            "total_lines": total_lines,
+            "diff": diff_result
+        });
+
+        if let Some(keys) = &self.keys {
+            value["keys"] = keys.iter().map(|change| serde_json::json!({
+                "path": change.path,
+                "old": change.old,
+                "new": change.new
+            })).collect();
+        }
+
+        if CONFIG.load().include_full_content && matches!(self.kind, NotificationKind::Created | NotificationKind::Modified) {
+            match &self.content {
+                Some(content) => value["content"] = content.as_str().into(),
+                None => value["content_omitted"] = true.into()
+            }
+        }
+
+        match &self.kind {
+            NotificationKind::Renamed { from, to } => {
+                value["from"] = shown(from).into();
+                value["to"] = shown(to).into();
+            },
+            NotificationKind::Metadata { change } => {
+                value["change"] = change.as_str().into();
+            },
+            NotificationKind::Started { files } => {
+                value["count"] = files.len().into();
+                value["files"] = files.iter().map(|file| shown(file)).collect::<Vec<_>>().into();
+            },
+            _ => {}
+        }
+
+        value
+    }
+
+    pub async fn notify(&self) -> Result<(), DeliveryError> {
+        post_all(std::slice::from_ref(self), false).await
+    }
+
+    /// Deliver several notifications in a single body, a JSON array for the
+    /// endpoints that take JSON
+    pub async fn notify_batch(notifs: &[Notification]) -> Result<(), DeliveryError> {
+        post_all(notifs, true).await
+    }
+}
+
+/// Token bucket refilled at `max_requests_per_second`, holding up to a second's worth
+struct RateLimiter {
+    tokens: f64,
+    refilled: Instant
+}
+
+impl RateLimiter {
+    /// Refill the bucket up to `now` and take a token from it, or tell how
+    /// long to wait until one is available
+    fn take(&mut self, rate: f64, now: Instant) -> Option<Duration> {
+        self.tokens = (self.tokens + now.duration_since(self.refilled).as_secs_f64() * rate).min(rate);
+        self.refilled = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(Duration::from_secs_f64((1.0 - self.tokens) / rate))
+        }
+    }
+}
+
+static LIMITER: Reloadable<tokio::sync::Mutex<RateLimiter>> = Reloadable::new(|| {
+    let tokens = CONFIG.load().max_requests_per_second.unwrap_or(0) as f64;
+    tokio::sync::Mutex::new(RateLimiter { tokens, refilled: Instant::now() })
+});
+
+/// Wait until another request may be sent. Waiting requests queue up on the
+/// lock, so they go out in the order they arrived
+async fn throttle() {
+    let rate = match CONFIG.load().max_requests_per_second {
+        Some(rate) if rate > 0 => rate as f64,
+        _ => return
+    };
+
+    let limiter = LIMITER.get();
+    let mut bucket = limiter.lock().await;
+    while let Some(wait) = bucket.take(rate, Instant::now()) {
+        tokio::time::sleep(wait).await;
+    }
+}
+
+/// HTTP client shared by every delivery, so connections to the endpoints are reused
+static CLIENT: Reloadable<reqwest::Client> = Reloadable::new(|| {
+    http_client(&CONFIG.load()).unwrap_or_else(|e| {
+        error!(error = %e, "failed to create the HTTP client, sending without the certificates");
+        reqwest::Client::new()
+    })
+});
+
+/// Read a certificate or a key named in the config, the error naming the key
+fn read_pem(key: &str, path: &Path) -> Result<Vec<u8>, String> {
+    fs::read(path).map_err(|e| format!("{}: cannot read {}: {}", key, path.display(), e))
+}
+
+/// Build the HTTP client with the timeout and the certificates of the config
+fn http_client(config: &Config) -> Result<reqwest::Client, String> {
+    let mut builder = reqwest::Client::builder();
+    if config.request_timeout_ms > 0 {
+        builder = builder.timeout(Duration::from_millis(config.request_timeout_ms));
+    }
+
+    if let (Some(cert_path), Some(key_path)) = (&config.client_cert_path, &config.client_key_path) {
+        let identity = reqwest::Identity::from_pkcs8_pem(&read_pem("client_cert_path", cert_path)?, &read_pem("client_key_path", key_path)?)
+            .map_err(|e| format!("client_cert_path: invalid certificate or key: {}", e))?;
+        builder = builder.identity(identity);
+    }
+
+    if let Some(ca_path) = &config.ca_cert_path {
+        let certificate = reqwest::Certificate::from_pem(&read_pem("ca_cert_path", ca_path)?)
+            .map_err(|e| format!("ca_cert_path: invalid certificate: {}", e))?;
+        builder = builder.add_root_certificate(certificate);
+    }
+
+    builder.build().map_err(|e| e.to_string())
+}
+
+/// Post the notifications to every configured endpoint in its own format
+async fn post_all(notifs: &[Notification], batch: bool) -> Result<(), DeliveryError> {
+    let mut failures: Vec<EndpointFailure> = Vec::new();
+    
+    for endpoint in &CONFIG.load().endpoints {
+        let notifs: Vec<&Notification> = notifs.iter().filter(|notif| endpoint.wants(notif)).collect();
+        if notifs.is_empty() {
+            continue;
+        }
+
+        // Templates describe a single notification, so batches are sent one by one
+        let bodies = if TEMPLATE.get().is_some() && endpoint.format == "json" {
+            notifs.iter().map(|notif| endpoint.body(&[notif], false)).collect()
+        } else {
+            vec![endpoint.body(&notifs, batch)]
+        };
+
+        for (body, content_type) in bodies {
+            if CONFIG.load().dry_run {
+                info!(endpoint = endpoint.url, content_type, body, "dry run, not sending");
+                continue;
+            }
+
+            if let Err(reason) = deliver(&CLIENT.get(), &endpoint.url, &body, &content_type).await {
+                METRICS.webhook_failures.with_label_values(&[endpoint.url.as_str()]).inc();
+                DeadLetter { endpoint: endpoint.url.clone(), content_type: content_type.to_string(), body }.append();
+                failures.push(EndpointFailure { endpoint: endpoint.url.clone(), reason });
+            }
+        }
+    }
+    
+    if failures.is_empty() {
+        if !CONFIG.load().dry_run {
+            STATUS.notifications_sent.fetch_add(notifs.len() as u64, Ordering::Relaxed);
+        }
+        Ok(())
+    } else {
+        Err(DeliveryError { failures })
+    }
+}
+
+/// Send the requests kept in `dead_letter_path` again, before the new ones
+pub async fn redeliver_dead_letters() {
+    DeadLetter::redeliver().await;
+}
+
+/// A request that couldn't be delivered, kept in `dead_letter_path`
+#[derive(Serialize, Deserialize)]
+struct DeadLetter {
+    endpoint: String,
+    content_type: String,
+    body: String
+}
+
+impl DeadLetter {
+    fn append(&self) {
+        let config = CONFIG.load();
+        let path = match &config.dead_letter_path {
+            Some(t) => t,
+            None => return
+        };
+
+        let result = serde_json::to_string(self).map_err(|e| e.to_string()).and_then(|line| {
+            fs::OpenOptions::new().create(true).append(true).open(path)
+                .and_then(|mut file| writeln!(file, "{}", line))
+                .map_err(|e| e.to_string())
+        });
+        if let Err(e) = result {
+            error!(path, error = %e, "failed to keep the undelivered request");
+        }
+    }
+
+    /// Send the requests kept from before again, keeping the ones that still
+    /// fail. Once an endpoint fails the rest of its requests aren't tried
+    async fn redeliver() {
+        let config = CONFIG.load();
+        let path = match &config.dead_letter_path {
+            Some(t) => t,
+            None => return
+        };
+
+        let contents = match fs::read_to_string(path) {
+            Ok(t) => t,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!(path, error = %e, "failed to read the undelivered requests");
+                }
+                return
+            }
+        };
+
+        let mut failed: HashSet<String> = HashSet::new();
+        let mut remaining: Vec<&str> = Vec::new();
+        let mut delivered = 0;
+
+        for line in contents.lines().filter(|line| !line.trim().is_empty()) {
+            let letter: DeadLetter = match serde_json::from_str(line) {
+                Ok(t) => t,
+                Err(e) => {
+                    warn!(path, error = %e, "dropping an unreadable undelivered request");
+                    continue
+                }
+            };
+
+            if failed.contains(&letter.endpoint) {
+                remaining.push(line);
+                continue;
+            }
+
+            match deliver(&CLIENT.get(), &letter.endpoint, &letter.body, &letter.content_type).await {
+                Ok(()) => delivered += 1,
+                Err(reason) => {
+                    warn!(endpoint = letter.endpoint, reason, "still failing to deliver");
+                    failed.insert(letter.endpoint);
+                    remaining.push(line);
+                }
+            }
+        }
+
+        info!(path, delivered, remaining = remaining.len(), "sent the undelivered requests again");
+        let result = if remaining.is_empty() {
+            fs::remove_file(path)
+        } else {
+            fs::write(path, remaining.join("\n") + "\n")
+        };
+        if let Err(e) = result {
+            error!(path, error = %e, "failed to update the undelivered requests");
+        }
+    }
+}
+
+/// Gzip the body when asked to and it's large enough to be worth it
+fn compress(body: &str) -> Option<Vec<u8>> {
+    if !CONFIG.load().compress_payloads || body.len() < COMPRESS_THRESHOLD_BYTES {
+        return None;
+    }
+
+    let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(body.as_bytes()).and_then(|_| encoder.finish())
+        .map_err(|e| warn!(error = %e, "failed to compress, sending as is"))
+        .ok()
+}
+
+/// Wait before the given retry, doubling with every attempt
+fn backoff(attempt: u32) -> u64 {
+    CONFIG.load().retry_base_ms.saturating_mul(1 << attempt.min(16))
+}
+
+/// Why a single attempt at sending failed
+enum Failure {
+    /// Worth trying again, like a dropped connection or a server error
+    Transient(String),
+    /// Bound to fail again, like a rejected request
+    Fatal(String)
+}
+
+/// Send with the given transport, waiting for the rate limit before every
+/// attempt and retrying the transient failures with an exponential backoff
+async fn with_retries<F, Fut>(mut send: F) -> Result<(), String>
+where F: FnMut() -> Fut, Fut: std::future::Future<Output = Result<(), Failure>> {
+    let mut attempt: u32 = 0;
+
+    loop {
+        throttle().await;
+        let reason = match send().await {
+            Ok(()) => {
+                debug!("delivered");
+                return Ok(())
+            },
+            Err(Failure::Fatal(reason)) => return Err(reason),
+            Err(Failure::Transient(reason)) => reason
+        };
+
+        if attempt >= CONFIG.load().retry_count {
+            return Err(format!("{} (after {} attempts)", reason, attempt + 1));
+        }
+
+        let backoff = backoff(attempt);
+        warn!(reason, backoff_ms = backoff, "delivery failed, retrying");
+        tokio::time::sleep(Duration::from_millis(backoff)).await;
+        attempt += 1;
+    }
+}
+
+/// The request posting a body to an endpoint, authenticated and with the
+/// headers of the config
+fn post(client: &reqwest::Client, endpoint: &str, body: &str, compressed: Option<&[u8]>, content_type: &str) -> reqwest::RequestBuilder {
+    let mut request = client.post(endpoint)
+        .header(reqwest::header::CONTENT_TYPE, content_type);
+
+    request = match compressed {
+        Some(bytes) => request.header(reqwest::header::CONTENT_ENCODING, "gzip").body(bytes.to_vec()),
+        None => request.body(body.to_string())
+    };
+
+    if let Some(token) = &CONFIG.load().auth_token {
+        request = request.bearer_auth(token);
+    }
+
+    for (name, value) in &CONFIG.load().headers {
+        request = request.header(name, value);
+    }
+
+    request
+}
+
+/// Post to a single endpoint, retrying connection failures and server
+/// errors with an exponential backoff
+#[instrument(skip(client, body, content_type))]
+async fn deliver(client: &reqwest::Client, endpoint: &str, body: &str, content_type: &str) -> Result<(), String> {
+    if endpoint.starts_with("ws://") || endpoint.starts_with("wss://") {
+        return send_ws(endpoint, body).await;
+    }
+    if let Some(path) = endpoint.strip_prefix("unix:") {
+        return send_unix(path, body).await;
+    }
+
+    let compressed = compress(body);
+
+    with_retries(|| {
+        let request = post(client, endpoint, body, compressed.as_deref(), content_type);
+        async move {
+            match request.send().await {
+                Ok(response) if response.status().is_success() => Ok(()),
+                Ok(response) if response.status().is_client_error() => {
+                    Err(Failure::Fatal(format!("rejected with {}", response.status())))
+                },
+                Ok(response) => Err(Failure::Transient(format!("responded with {}", response.status()))),
+                Err(e) if e.is_builder() => Err(Failure::Fatal(e.to_string())),
+                Err(e) => Err(Failure::Transient(e.to_string()))
+            }
+        }
+    }).await
+}
+
+type Socket = WebSocketStream<MaybeTlsStream<tokio::net::TcpStream>>;
+
+/// The connection to a WebSocket endpoint while it's open, locked by the
+/// one notification being sent over it
+type SocketSlot = Arc<tokio::sync::Mutex<Option<Socket>>>;
+
+/// Open WebSocket connections, kept alive between notifications per endpoint.
+/// Each has its own lock, so a slow endpoint doesn't hold up the others
+static SOCKETS: Lazy<std::sync::Mutex<HashMap<String, SocketSlot>>> = Lazy::new(|| {
+    std::sync::Mutex::new(HashMap::new())
+});
+
+/// TLS settings for the WebSocket connections, with the same certificates
+/// as the HTTP client. Nothing when the config doesn't name any
+static WS_TLS: Reloadable<Option<native_tls::TlsConnector>> = Reloadable::new(|| {
+    ws_tls(&CONFIG.load()).unwrap_or_else(|e| {
+        error!(error = %e, "failed to set up TLS for the WebSockets, connecting without the certificates");
+        None
+    })
+});
+
+/// Build the TLS connector for `client_cert_path` and `ca_cert_path`
+fn ws_tls(config: &Config) -> Result<Option<native_tls::TlsConnector>, String> {
+    if config.client_cert_path.is_none() && config.ca_cert_path.is_none() {
+        return Ok(None);
+    }
+
+    let mut builder = native_tls::TlsConnector::builder();
+    if let (Some(cert_path), Some(key_path)) = (&config.client_cert_path, &config.client_key_path) {
+        let identity = native_tls::Identity::from_pkcs8(&read_pem("client_cert_path", cert_path)?, &read_pem("client_key_path", key_path)?)
+            .map_err(|e| format!("client_cert_path: invalid certificate or key: {}", e))?;
+        builder.identity(identity);
+    }
+
+    if let Some(ca_path) = &config.ca_cert_path {
+        let certificate = native_tls::Certificate::from_pem(&read_pem("ca_cert_path", ca_path)?)
+            .map_err(|e| format!("ca_cert_path: invalid certificate: {}", e))?;
+        builder.add_root_certificate(certificate);
+    }
+
+    builder.build().map(Some).map_err(|e| e.to_string())
+}
+
+/// Open a connection to a WebSocket endpoint, authenticating the handshake
+/// the same way as the HTTP requests, with the same certificates
+async fn connect_ws(endpoint: &str) -> Result<Socket, String> {
+    let mut request = endpoint.into_client_request().map_err(|e| e.to_string())?;
+
+    if let Some(token) = &CONFIG.load().auth_token {
+        let value = HeaderValue::from_str(&format!("Bearer {}", token)).map_err(|e| e.to_string())?;
+        request.headers_mut().insert("Authorization", value);
+    }
+
+    for (name, value) in &CONFIG.load().headers {
+        let name = HeaderName::from_bytes(name.as_bytes()).map_err(|e| e.to_string())?;
+        let value = HeaderValue::from_str(value).map_err(|e| e.to_string())?;
+        request.headers_mut().insert(name, value);
+    }
+
+    let connector = WS_TLS.get().as_ref().clone().map(tokio_tungstenite::Connector::NativeTls);
+    let (socket, _) = tokio_tungstenite::connect_async_tls_with_config(request, None, false, connector).await
+        .map_err(|e| e.to_string())?;
+    debug!(endpoint, "connected");
+    Ok(socket)
+}
+
+/// Send a body as a text frame over the endpoint's long-lived connection,
+/// reconnecting with an exponential backoff whenever it drops
+async fn send_ws(endpoint: &str, body: &str) -> Result<(), String> {
+    let slot = SOCKETS.lock().unwrap().entry(endpoint.to_string()).or_default().clone();
+
+    // The connection is only locked while it's being used, not while
+    // waiting to retry
+    with_retries(|| {
+        let slot = slot.clone();
+        async move {
+            let mut socket = slot.lock().await;
+            let mut connected = match socket.take() {
+                Some(connected) => connected,
+                None => connect_ws(endpoint).await.map_err(Failure::Transient)?
+            };
+
+            connected.send(Message::text(body)).await.map_err(|e| Failure::Transient(e.to_string()))?;
+            *socket = Some(connected);
+            Ok(())
+        }
+    }).await
+}
+
+/// The connection to a Unix socket while it's open, locked like `SocketSlot`
+#[cfg(unix)]
+type UnixSlot = Arc<tokio::sync::Mutex<Option<tokio::net::UnixStream>>>;
+
+/// Open Unix socket connections, kept between notifications per socket path
+#[cfg(unix)]
+static UNIX_SOCKETS: Lazy<std::sync::Mutex<HashMap<String, UnixSlot>>> = Lazy::new(|| {
+    std::sync::Mutex::new(HashMap::new())
+});
+
+/// Write a body as one line to a local Unix socket, reconnecting with an
+/// exponential backoff whenever the connection drops
+#[cfg(unix)]
+async fn send_unix(path: &str, body: &str) -> Result<(), String> {
+    use tokio::io::AsyncWriteExt;
+
+    let slot = UNIX_SOCKETS.lock().unwrap().entry(path.to_string()).or_default().clone();
+
+    with_retries(|| {
+        let slot = slot.clone();
+        async move {
+            let mut stream = slot.lock().await;
+            let mut connected = match stream.take() {
+                Some(connected) => connected,
+                None => tokio::net::UnixStream::connect(path).await.map_err(|e| Failure::Transient(e.to_string()))?
+            };
+
+            connected.write_all(format!("{}\n", body).as_bytes()).await.map_err(|e| Failure::Transient(e.to_string()))?;
+            *stream = Some(connected);
+            Ok(())
+        }
+    }).await
+}
+
+#[cfg(not(unix))]
+async fn send_unix(_path: &str, _body: &str) -> Result<(), String> {
+    Err("Unix sockets aren't available on this platform".to_string())
+}
+
+/// Connect to a Unix socket and write a body to it once, without keeping
+/// the connection
+#[cfg(unix)]
+async fn write_unix_once(path: &str, body: &str) -> Result<(), String> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut stream = tokio::net::UnixStream::connect(path).await.map_err(|e| e.to_string())?;
+    stream.write_all(format!("{}\n", body).as_bytes()).await.map_err(|e| e.to_string())?;
+    let _ = stream.shutdown().await;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+async fn write_unix_once(path: &str, body: &str) -> Result<(), String> {
+    send_unix(path, body).await
+}
+
+/// Say goodbye to the WebSocket endpoints before exiting, and close the
+/// Unix sockets
+pub async fn close_sockets() {
+    let slots: Vec<SocketSlot> = SOCKETS.lock().unwrap().drain().map(|(_, slot)| slot).collect();
+    for slot in slots {
+        if let Some(mut socket) = slot.lock().await.take() {
+            let _ = socket.close(None).await;
+        }
+    }
+
+    #[cfg(unix)]
+    {
+        let slots: Vec<UnixSlot> = UNIX_SOCKETS.lock().unwrap().drain().map(|(_, slot)| slot).collect();
+        for slot in slots {
+            if let Some(mut stream) = slot.lock().await.take() {
+                let _ = tokio::io::AsyncWriteExt::shutdown(&mut stream).await;
+            }
+        }
+    }
+}
+
+/// How an endpoint took a sample notification
+pub struct EndpointCheck {
+    pub endpoint: String,
+    /// The status it responded with, or why it didn't accept the notification
+    pub outcome: Result<String, String>,
+    pub latency: Duration
+}
+
+/// Send a made up notification to every endpoint of the config once, in its
+/// format and without retrying, to tell whether they're reachable and accept it
+pub async fn check_endpoints(config: Config) -> Result<Vec<EndpointCheck>, WatcherError> {
+    config.validate().map_err(ConfigError::Invalid)?;
+    settle(config)?;
+
+    let mut sample = Notification::new(Path::new("watcher-test.txt"), NotificationKind::Modified);
+    sample.diff = vec![diff::Result::Left("before".to_string()), diff::Result::Right("after".to_string())];
+    sample.size = Some(6);
+
+    let mut checks = Vec::new();
+    for endpoint in &CONFIG.load().endpoints {
+        let (body, content_type) = endpoint.body(&[&sample], CONFIG.load().batch_notifications);
+        let started = Instant::now();
+
+        let outcome = if endpoint.url.starts_with("ws://") || endpoint.url.starts_with("wss://") {
+            match connect_ws(&endpoint.url).await {
+                Ok(mut socket) => {
+                    let sent = socket.send(Message::text(body)).await.map_err(|e| e.to_string());
+                    let _ = socket.close(None).await;
+                    sent.map(|_| "sent over WebSocket".to_string())
+                },
+                Err(e) => Err(e)
+            }
+        } else if let Some(path) = endpoint.url.strip_prefix("unix:") {
+            write_unix_once(path, &body).await.map(|_| "written to the socket".to_string())
+        } else {
+            let compressed = compress(&body);
+            match post(&CLIENT.get(), &endpoint.url, &body, compressed.as_deref(), &content_type).send().await {
+                Ok(response) if response.status().is_success() => Ok(response.status().to_string()),
+                Ok(response) => Err(format!("responded with {}", response.status())),
+                Err(e) => Err(e.to_string())
+            }
+        };
+
+        checks.push(EndpointCheck { endpoint: endpoint.url.clone(), outcome, latency: started.elapsed() });
+    }
+
+    Ok(checks)
+}
+
+#[derive(Debug, thiserror::Error)]
+#[error("{endpoint}: {reason}")]
+struct EndpointFailure {
+    endpoint: String,
+    reason: String
+}
+
+/// Endpoints a notification ultimately couldn't be delivered to
+#[derive(Debug, thiserror::Error)]
+#[error("Failed to deliver to {} endpoint(s){}", .failures.len(), .failures.iter().map(|failure| format!("\n  {}", failure)).collect::<String>())]
+pub struct DeliveryError {
+    failures: Vec<EndpointFailure>
+}
+
+/// A node of a tree as it's listed, one per line
+pub struct TreeLine {
+    /// Number of folders between the root and the node
+    pub depth: usize,
+    pub name: String,
+    /// Last modification of a file, nothing for folders
+    pub modified: Option<SystemTime>
+}
+
+pub struct FileTree {
+    root: PathBuf,
+    head: Box<Node>,
+    /// Set while the root is gone or unreadable
+    lost: Option<Lost>,
+    /// The root is a single file rather than a folder
+    single_file: bool,
+    /// The single file was deleted, and is waited for to be created again
+    file_gone: bool,
+}
+
+/// When a lost root is looked at again, backing off the longer it stays gone
+struct Lost {
+    retry_at: Instant,
+    backoff: Duration
+}
+
+/// Longest wait between two looks at a lost root
+const ROOT_RETRY_MAX: Duration = Duration::from_secs(60);
+
+impl FileTree {
+    pub fn new(root: &Path) -> FileTree {
+        FileTree { root: root.to_path_buf(), head: Box::new(Node::new()), lost: None, single_file: root.is_file(), file_gone: false }
+    }
+
+    /// What the OS is asked to report the events of. A single file is watched
+    /// through its folder, so it's still seen when it's created again
+    fn watched_path(&self) -> (&Path, RecursiveMode) {
+        match self.root.parent() {
+            Some(parent) if self.single_file => (parent, RecursiveMode::NonRecursive),
+            _ => (&self.root, RecursiveMode::Recursive)
+        }
+    }
+
+    /// Whether the root can be read, logging when it's lost and when it's
+    /// back. The tree is left as it was while the root is lost, so whatever
+    /// changed in the meantime is reported once it's back
+    fn root_readable(&mut self) -> bool {
+        let now = Instant::now();
+        if self.lost.as_ref().is_some_and(|lost| now < lost.retry_at) {
+            return false;
+        }
+
+        // A single file coming and going is reported as created and deleted
+        let readable = self.single_file || self.root.read_dir().is_ok();
+        match (&mut self.lost, readable) {
+            (None, true) => {},
+            (Some(_), true) => {
+                info!(root = %self.root.display(), "root is back, watching it again");
+                self.lost = None;
+            },
+            (None, false) => {
+                error!(root = %self.root.display(), "root was deleted or can't be read anymore");
+                let backoff = CONFIG.load().poll_interval();
+                self.lost = Some(Lost { retry_at: now + backoff, backoff });
+            },
+            (Some(lost), false) => {
+                lost.backoff = (lost.backoff * 2).min(ROOT_RETRY_MAX);
+                lost.retry_at = now + lost.backoff;
+                debug!(root = %self.root.display(), retry_in_ms = lost.backoff.as_millis() as u64, "root still lost");
+            }
+        }
+
+        readable
+    }
+
+    pub fn fill(&mut self) {
+        let _span = info_span!("fill", root = %self.root.display()).entered();
+
+        let root = self.root.clone();
+        self.head.fill(&root);
+    }
+
+    pub fn display(&self) {
+        self.head.display("");
+    }
+
+    /// Every node of the tree in the order `display` prints them
+    pub fn lines(&self) -> Vec<TreeLine> {
+        let mut lines: Vec<TreeLine> = Vec::new();
+        self.head.lines(&mut lines);
+        lines
+    }
+
+    /// Reconcile the snapshot with the disk in a single walk, updating it in
+    /// place and returning a notification for every modified, created or
+    /// deleted file
+    pub fn sync(&mut self) -> Vec<Notification> {
+        let _span = debug_span!("poll", root = %self.root.display()).entered();
+
+        let mut buffer: Vec<Notification> = Vec::new();
+        if self.file_gone {
+            if self.root.is_file() {
+                *self.head = Node::new();
+                self.fill();
+                self.head.notify_creation(&mut buffer);
+                self.file_gone = false;
+            }
+        } else if !self.head.poll(&mut buffer) && self.single_file {
+            self.file_gone = true;
+        }
+        debug!(notifications = buffer.len(), "poll finished");
+        buffer
+    }
+
+    /// Queue a notification for every file that changed since the saved state
+    /// was recorded. Changes come with a diff when the old contents were saved
+    fn restore(&mut self, state: &State, buffer: &mut Vec<Notification>) {
+        let mut current: BTreeMap<PathBuf, FileState> = BTreeMap::new();
+        self.head.record(&mut current, false);
+        current.remove(&State::path());
+
+        for (path, saved) in state.files.iter().filter(|(path, _)| path.starts_with(&self.root)) {
+            if current.contains_key(path) {
+                continue;
+            }
+
+            let mut notif = Notification::new(path, NotificationKind::Deleted);
+            notif.size = Some(0);
+            notif.hash = saved.hash.clone();
+            if let Some(content) = &saved.content {
+                notif.diff = content.lines().map(|l| diff::Result::Left(l.to_string())).collect();
+            }
+            buffer.push(notif);
+        }
+
+        for (path, now) in &current {
+            match state.files.get(path) {
+                Some(saved) if saved == now => {},
+                // Files with the same contents are only touched, like on a fresh checkout
+                Some(saved) if saved.hash.is_some() && saved.hash == now.hash => {},
+                // Unread files can only be told apart by their mtime and size
+                Some(saved) if now.hash.is_none() && saved.mtime == now.mtime && saved.size == now.size => {},
+                Some(saved) => match (self.head.find_mut(path), &saved.content) {
+                    // Diffed like a change seen while watching, from the saved contents
+                    (Some(node), Some(old)) if node.content.is_some() => {
+                        let new = node.content.replace(old.clone());
+                        node.notify_change(new, buffer);
+                    },
+                    _ => {
+                        let mut notif = Notification::new(path, NotificationKind::Modified);
+                        notif.size = now.size;
+                        buffer.push(notif);
+                    }
+                },
+                None => if let Some(node) = self.head.find_mut(path) {
+                    node.notify_creation(buffer);
+                }
+            }
+        }
+    }
+
+    /// Bring the node for a path reported by the filesystem in line with the disk
+    fn reconcile(&mut self, path: &Path, buffer: &mut Vec<Notification>) {
+        if !path.exists() {
+            if let Some(node) = self.head.remove(path) {
+                node.notify_removal(buffer);
+
+                // A folder that's gone as well gets reported as deleted instead
+                let parent = path.parent().and_then(|parent| self.head.find_mut(parent));
+                if let Some(parent) = parent.filter(|parent| parent.children.is_empty() && parent.path.exists()) {
+                    buffer.push(Notification::new(&parent.path, NotificationKind::FolderEmptied));
+                }
+            }
+            return;
+        }
+
+        if let Some(node) = self.head.find_mut(path) {
+            if matches!(node.kind, NodeType::File) {
+                let _ = node.poll(buffer);
+            }
+            return;
+        }
+
+        if is_ignored(path, path.is_dir(), true) || !in_scope(path, path.is_dir()) {
+            return;
+        }
+
+        // Anything within a hidden folder is hidden as well
+        let hidden = !CONFIG.load().watch_hidden && path.ancestors()
+            .take_while(|ancestor| *ancestor != self.root)
+            .any(is_hidden);
+        if hidden {
+            return;
+        }
+
+        let depth = path.strip_prefix(&self.root).map_or(0, |rest| rest.components().count());
+        if CONFIG.load().max_depth.is_some_and(|max| depth > max) {
+            return;
+        }
+
+        let mut node = Node::new();
+        node.fill_tracked(path, depth, &mut HashSet::new(), false);
+
+        if node.path == Path::new("...") {
+            return;
+        }
+
+        if node.is_prunable() {
+            return;
+        }
+
+        if admit(&mut node, buffer) {
+            self.head.insert(node);
+        }
+    }
+}
+
+/// Largest contents kept in the saved state, for what changed while the
+/// watcher wasn't running to be diffed. Larger files are reported without one
+const STATE_CONTENT_MAX_BYTES: usize = 64 * 1024;
+
+/// What is known about a file between two runs of the watcher
+#[derive(Serialize, Deserialize, Default, PartialEq)]
+#[serde(default)]
+struct FileState {
+    mtime: Option<u128>,
+    hash: Option<String>,
+    size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<String>
+}
+
+/// The files of every tree as they were when the watcher stopped. Unknown
+/// fields are ignored, so newer versions can add to it
+#[derive(Serialize, Deserialize, Default)]
+#[serde(default)]
+struct State {
+    version: u32,
+    files: BTreeMap<PathBuf, FileState>
+}
+
+impl State {
+    const VERSION: u32 = 1;
+
+    fn path() -> PathBuf {
+        let path = PathBuf::from(CONFIG.load().state_path.as_deref().unwrap_or(".watcher-state.json"));
+        std::path::absolute(&path).unwrap_or(path)
+    }
+
+    /// Read the saved state, nothing on the first run
+    fn load() -> Option<State> {
+        let path = State::path();
+        let contents = match fs::read_to_string(&path) {
+            Ok(t) => t,
+            Err(e) => {
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    warn!(path = %path.display(), error = %e, "failed to read the saved state");
+                }
+                return None
+            }
+        };
+
+        serde_json::from_str(&contents).map_err(|e| {
+            warn!(path = %path.display(), error = %e, "ignoring unreadable saved state");
+        }).ok()
+    }
+
+    fn save(mut self) -> Result<(), WatcherError> {
+        self.version = State::VERSION;
+
+        // The state file may well be watched itself, but it's bound to change
+        let path = State::path();
+        self.files.remove(&path);
+
+        serde_json::to_string(&self).map_err(|e| e.to_string())
+            .and_then(|json| fs::write(&path, json).map_err(|e| e.to_string()))
+            .map_err(|reason| WatcherError::SaveState { path, reason })
+    }
+}
+
+/// Merge every deletion with a creation of the exact same contents into a
+/// single rename
+fn pair_renames(buffer: &mut Vec<Notification>) {
+    let (mut created, others): (Vec<Notification>, Vec<Notification>) = std::mem::take(buffer).into_iter()
+        .partition(|notif| matches!(notif.kind, NotificationKind::Created) && notif.hash.is_some());
+
+    for mut notif in others {
+        let moved = match notif.kind {
+            NotificationKind::Deleted if notif.hash.is_some() => created.iter().position(|c| c.hash == notif.hash),
+            _ => None
+        };
+
+        if let Some(index) = moved {
+            let to = created.remove(index);
+            notif.kind = NotificationKind::Renamed { from: notif.path, to: to.path.clone() };
+            notif.path = to.path;
+            notif.time = to.time;
+            notif.size = to.size;
+            notif.diff.clear();
+        }
+
+        buffer.push(notif);
+    }
+
+    buffer.extend(created);
+}
+
+/// Hand every queued notification to the callback in the order they were
+/// detected, waiting for it to take each of them
+async fn dispatch<F, Fut>(buffer: &mut Vec<Notification>, callback: &mut F)
+where F: FnMut(Notification) -> Fut, Fut: std::future::Future<Output = ()> {
+    pair_renames(buffer);
+    buffer.sort_by_key(|notif| notif.time);
+
+    for mut notif in buffer.drain(..) {
+        notif.seq = SEQ.fetch_add(1, Ordering::Relaxed);
+        METRICS.notifications.inc();
+        if CONFIG.load().sse_addr.is_some() {
+            let _ = EVENTS.send(notif.json());
+        }
+        if !CONFIG.load().file_sinks.is_empty() {
+            write_sinks(&notif);
+        }
+        callback(notif).await;
+    }
+}
+
+/// A sink file kept open, along with what identifies it on the disk
+struct OpenSink {
+    file: fs::File,
+    id: Option<(u64, u64)>
+}
+
+/// The `file_sinks` opened so far, by path
+static SINK_FILES: Lazy<std::sync::Mutex<HashMap<PathBuf, OpenSink>>> = Lazy::new(|| {
+    std::sync::Mutex::new(HashMap::new())
+});
+
+/// The device and inode of a file, which change when it's rotated
+fn file_id(metadata: &fs::Metadata) -> Option<(u64, u64)> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some((metadata.dev(), metadata.ino()))
+    }
+
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
+
+/// Append the notification to the sink files that want it, opening them
+/// again when they were moved away or replaced, like by logrotate
+fn write_sinks(notif: &Notification) {
+    write_to(&CONFIG.load().file_sinks, notif);
+}
+
+/// Append the notification to the given sinks that want it
+fn write_to(sinks: &[FileSink], notif: &Notification) {
+    let mut files = SINK_FILES.lock().unwrap();
+    let mut line = None;
+
+    for sink in sinks.iter().filter(|sink| sink.wants(notif)) {
+        let line = line.get_or_insert_with(|| notif.json() + "\n");
+
+        let on_disk = fs::metadata(&sink.path).ok();
+        let rotated = files.get(&sink.path).is_some_and(|open| match &on_disk {
+            Some(metadata) => open.id != file_id(metadata),
+            None => true
+        });
+        if rotated {
+            debug!(path = %sink.path.display(), "sink file rotated, opening it again");
+            files.remove(&sink.path);
+        }
+
+        let open = match files.entry(sink.path.clone()) {
+            std::collections::hash_map::Entry::Occupied(entry) => entry.into_mut(),
+            std::collections::hash_map::Entry::Vacant(entry) => {
+                let opened = fs::OpenOptions::new().create(true).append(true).open(&sink.path)
+                    .and_then(|file| Ok(OpenSink { id: file_id(&file.metadata()?), file }));
+                match opened {
+                    Ok(open) => entry.insert(open),
+                    Err(e) => {
+                        warn!(path = %sink.path.display(), error = %e, "failed to open the sink file");
+                        continue;
+                    }
+                }
+            }
+        };
+
+        if let Err(e) = open.file.write_all(line.as_bytes()) {
+            warn!(path = %sink.path.display(), error = %e, "failed to write to the sink file");
+            files.remove(&sink.path);
+        }
+    }
+}
+
+/// Sequence number of the next notification emitted
+static SEQ: AtomicU64 = AtomicU64::new(0);
+
+/// Sync the trees with the disk on every interval
+async fn watch_poll<F, Fut, G>(trees: &mut [FileTree], mut notifications: Vec<Notification>, mut callback: F, mut synced: G) -> Result<(), WatcherError>
+where F: FnMut(Notification) -> Fut, Fut: std::future::Future<Output = ()>, G: FnMut(&[FileTree]) {
+    let mut result = Ok(());
+    let mut idle: u32 = 0;
+    let mut reloaded = RELOADED.subscribe();
+    let mut reload_due = false;
+
+    loop {
+        let started = Instant::now();
+        let mut changed = false;
+        for ft in trees.iter_mut() {
+            if ft.root_readable() {
+                let found = ft.sync();
+                changed |= !found.is_empty();
+                notifications.extend(found);
+            } else if CONFIG.load().exit_on_root_lost {
+                result = Err(WatcherError::RootLost(ft.root.clone()));
+            }
+        }
+        STATUS.record_cycle(started.elapsed());
+
+        let interval = if changed {
+            idle = 0;
+            CONFIG.load().poll_interval()
+        } else {
+            idle = idle.saturating_add(1);
+            CONFIG.load().idle_poll_interval(idle)
+        };
+        if interval > CONFIG.load().poll_interval() {
+            debug!(idle_cycles = idle, interval_ms = interval.as_millis() as u64, "backing off while idle");
+        }
+        synced(trees);
+        dispatch(&mut notifications, &mut callback).await;
+
+        if result.is_err() {
+            break;
+        }
+
+        // A reloaded config takes over once this poll's changes are reported
+        if std::mem::take(&mut reload_due) && rebuild(trees) {
+            synced(trees);
+        }
+
+        tokio::select! {
+            _ = tokio::time::sleep(interval) => {},
+            Ok(()) = reloaded.changed() => reload_due = true,
+            _ = shutdown_requested() => break
+        }
+    }
+
+    dispatch(&mut notifications, &mut callback).await;
+    result
+}
+
+/// Files left out for `max_watched_files`, which are taken in without being
+/// reported once there's room for them, as they were there all along
+static LEFT_OUT: Lazy<std::sync::Mutex<HashSet<PathBuf>>> = Lazy::new(Default::default);
+
+/// Whether `max_watched_files` are watched already
+fn at_file_cap() -> bool {
+    CONFIG.load().max_watched_files.is_some_and(|max| STATUS.watched_files.load(Ordering::Relaxed) >= max)
+}
+
+/// Warn the first time files are left out for `max_watched_files`
+fn note_cap_reached(max: usize) {
+    if !STATUS.file_cap_reached.swap(true, Ordering::Relaxed) {
+        warn!(max_watched_files = max, "watching as many files as allowed, leaving out the others");
+    }
+}
+
+/// Leave out the files past `max_watched_files` once the trees are filled.
+/// The first ones in path order are kept, so the same files are watched
+/// whatever order the folders were read in
+fn cap_files(trees: &mut [FileTree]) {
+    let mut files: Vec<PathBuf> = Vec::new();
+    for ft in trees.iter() {
+        ft.head.file_paths(&mut files);
+    }
+
+    let max = CONFIG.load().max_watched_files.unwrap_or(usize::MAX);
+    let left_out = past_room(&mut files, max);
+    STATUS.watched_files.store(files.len(), Ordering::Relaxed);
+
+    if !left_out.is_empty() {
+        note_cap_reached(max);
+        for ft in trees.iter_mut() {
+            ft.head.drop_files(&left_out);
+        }
+    }
+    *LEFT_OUT.lock().unwrap() = left_out;
+}
+
+/// Sort the files and take out the ones past the first `room` of them
+fn past_room(files: &mut Vec<PathBuf>, room: usize) -> HashSet<PathBuf> {
+    files.sort();
+    files.split_off(room.min(files.len())).into_iter().collect()
+}
+
+/// Count the files of a subtree found while watching, leaving out the ones
+/// past `max_watched_files` in path order, and report the ones that are new.
+/// Returns whether anything is left of the subtree to be added
+fn admit(node: &mut Node, buffer: &mut Vec<Notification>) -> bool {
+    let Some(max) = CONFIG.load().max_watched_files else {
+        node.notify_creation(buffer);
+        return true
+    };
+
+    let mut files: Vec<PathBuf> = Vec::new();
+    node.file_paths(&mut files);
+
+    let room = max.saturating_sub(STATUS.watched_files.load(Ordering::Relaxed));
+    let dropped = past_room(&mut files, room);
+    STATUS.watched_files.fetch_add(files.len(), Ordering::Relaxed);
+
+    let mut left_out = LEFT_OUT.lock().unwrap();
+    if !dropped.is_empty() {
+        note_cap_reached(max);
+        if matches!(node.kind, NodeType::File) {
+            left_out.extend(dropped);
+            return false
+        }
+        node.drop_files(&dropped);
+        left_out.extend(dropped);
+    }
+
+    let mut created: Vec<Notification> = Vec::new();
+    node.notify_creation(&mut created);
+    buffer.extend(created.into_iter().filter(|notif| !left_out.remove(&notif.path)));
+    !node.is_prunable()
+}
+
+/// Put the config given to `reload` in effect and build the trees again from
+/// the disk, for its targets and ignore rules to apply. Meant for once the
+/// changes found under the current config are reported, as the files that
+/// come into or go out of scope that way aren't. Tells whether it did
+fn rebuild(trees: &mut [FileTree]) -> bool {
+    if !apply_reload() {
+        return false
+    }
+
+    for ft in trees.iter_mut().filter(|ft| ft.lost.is_none() && !ft.file_gone) {
+        *ft.head = Node::new();
+        ft.fill();
+    }
+    cap_files(trees);
+    true
+}
+
+/// React to the events reported by the OS instead of polling the tree
+async fn watch_events<F, Fut, G>(trees: &mut [FileTree], mut notifications: Vec<Notification>, mut callback: F, mut synced: G) -> Result<(), WatcherError>
+where F: FnMut(Notification) -> Fut, Fut: std::future::Future<Output = ()>, G: FnMut(&[FileTree]) {
+    let (tx, mut rx) = tokio::sync::mpsc::unbounded_channel();
+    let mut watcher = notify::recommended_watcher(move |res| {
+        let _ = tx.send(res);
+    })?;
+
+    for ft in trees.iter() {
+        let (path, mode) = ft.watched_path();
+        watcher.watch(path, mode).map_err(|e| e.add_path(path.to_path_buf()))?;
+    }
+
+    dispatch(&mut notifications, &mut callback).await;
+
+    let mut result = Ok(());
+    let mut reloaded = RELOADED.subscribe();
+    let mut roots_check = tokio::time::interval(CONFIG.load().poll_interval());
+    roots_check.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        let res = tokio::select! {
+            res = rx.recv() => match res {
+                Some(t) => t,
+                None => break
+            },
+            _ = roots_check.tick() => {
+                // The OS stops reporting anything once a root is gone, so it's
+                // watched again and caught up with by polling once it's back
+                for ft in trees.iter_mut() {
+                    let was_lost = ft.lost.is_some();
+                    if ft.root_readable() {
+                        if was_lost {
+                            let (path, mode) = ft.watched_path();
+                            if let Err(e) = watcher.watch(path, mode) {
+                                error!(root = %ft.root.display(), error = %e, "failed to watch the root again");
+                            }
+                            notifications.extend(ft.sync());
+                        }
+                    } else if CONFIG.load().exit_on_root_lost {
+                        result = Err(WatcherError::RootLost(ft.root.clone()));
+                    }
+                }
+
+                synced(trees);
+                dispatch(&mut notifications, &mut callback).await;
+                if result.is_err() {
+                    break;
+                }
+                continue
+            },
+            Ok(()) = reloaded.changed() => {
+                // What changed under the current config is reported before
+                // the reloaded one takes over
+                for ft in trees.iter_mut().filter(|ft| ft.lost.is_none()) {
+                    notifications.extend(ft.sync());
+                }
+                synced(trees);
+                dispatch(&mut notifications, &mut callback).await;
+
+                if rebuild(trees) {
+                    synced(trees);
+                }
+                continue
+            },
+            _ = shutdown_requested() => break
+        };
+
+        // Both ends of a rename are reported as separate events, so everything
+        // already queued is handled together for them to be paired up
+        let mut results = vec![res];
+        while let Ok(res) = rx.try_recv() {
+            results.push(res);
+        }
+
+        let timer = METRICS.poll_duration.start_timer();
+        for res in results {
+            let event: notify::Event = match res {
+                Ok(t) => t,
+                _ => continue
+            };
+
+            if !matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_) | EventKind::Remove(_)) {
+                continue;
+            }
+
+            for path in &event.paths {
+                if let Some(ft) = trees.iter_mut().find(|ft| path.starts_with(&ft.root) && ft.lost.is_none()) {
+                    if ft.single_file {
+                        notifications.extend(ft.sync());
+                    } else {
+                        ft.reconcile(path, &mut notifications);
+                    }
+                }
+            }
+        }
+        timer.observe_duration();
+
+        synced(trees);
+        dispatch(&mut notifications, &mut callback).await;
+    }
+
+    dispatch(&mut notifications, &mut callback).await;
+    result
+}
+
+/// What the status endpoint reports about the running watcher
+struct Status {
+    /// Counted up as files are added, and set to the files in the trees
+    /// every time they're synced
+    watched_files: AtomicUsize,
+    /// Files were left out for `max_watched_files`
+    file_cap_reached: AtomicBool,
+    /// Millis since the epoch of the last time the trees were synced, zero before that
+    last_poll_ms: AtomicU64,
+    notifications_sent: AtomicU64,
+    /// Time the last poll took to sync the trees
+    last_cycle_ms: AtomicU64,
+    /// Polls that took longer than the poll interval
+    slow_cycles: AtomicU64
+}
+
+impl Status {
+    /// Note that the trees were just brought up to date
+    fn record_poll(&self, trees: &[FileTree]) {
+        let files = trees.iter().map(|ft| ft.head.count_files()).sum();
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |t| t.as_millis() as u64);
+
+        self.watched_files.store(files, Ordering::Relaxed);
+        METRICS.files_watched.set(files as i64);
+        self.last_poll_ms.store(now, Ordering::Relaxed);
+    }
+
+    /// Note how long a poll took, warning when it took longer than the interval
+    fn record_cycle(&self, took: Duration) {
+        METRICS.poll_duration.observe(took.as_secs_f64());
+        self.last_cycle_ms.store(took.as_millis() as u64, Ordering::Relaxed);
+
+        let interval = CONFIG.load().poll_interval();
+        if took > interval {
+            self.slow_cycles.fetch_add(1, Ordering::Relaxed);
+            METRICS.slow_cycles.inc();
+            warn!(took_ms = took.as_millis() as u64, interval_ms = interval.as_millis() as u64,
+                "polling took longer than the interval, changes are reported late");
+        }
+    }
+
+    fn json(&self) -> serde_json::Value {
+        let last_poll = match self.last_poll_ms.load(Ordering::Relaxed) {
+            0 => None,
+            ms => DateTime::<Utc>::from_timestamp_millis(ms as i64).map(|t| t.to_rfc3339())
+        };
+
+        serde_json::json!({
+            "watched_files": self.watched_files.load(Ordering::Relaxed),
+            "last_poll": last_poll,
+            "poll_interval_ms": CONFIG.load().poll_interval().as_millis() as u64,
+            "notifications_sent": self.notifications_sent.load(Ordering::Relaxed),
+            "last_cycle_ms": self.last_cycle_ms.load(Ordering::Relaxed),
+            "slow_cycles": self.slow_cycles.load(Ordering::Relaxed),
+            "max_watched_files": CONFIG.load().max_watched_files,
+            "max_watched_files_reached": self.file_cap_reached.load(Ordering::Relaxed)
+        })
+    }
+}
+
+static STATUS: Status = Status {
+    watched_files: AtomicUsize::new(0),
+    file_cap_reached: AtomicBool::new(false),
+    last_poll_ms: AtomicU64::new(0),
+    notifications_sent: AtomicU64::new(0),
+    last_cycle_ms: AtomicU64::new(0),
+    slow_cycles: AtomicU64::new(0)
+};
+
+/// Prometheus metrics, served on `metrics_addr`
+struct Metrics {
+    registry: prometheus::Registry,
+    notifications: prometheus::IntCounter,
+    webhook_failures: prometheus::IntCounterVec,
+    files_watched: prometheus::IntGauge,
+    /// Time taken to bring the trees up to date, a poll or a batch of events
+    poll_duration: prometheus::Histogram,
+    slow_cycles: prometheus::IntCounter
+}
+
+static METRICS: Lazy<Metrics> = Lazy::new(|| {
+    let metrics = Metrics {
+        registry: prometheus::Registry::new(),
+        notifications: prometheus::IntCounter::new("watcher_notifications_total", "Notifications emitted")
+            .expect("Invalid metric"),
+        webhook_failures: prometheus::IntCounterVec::new(
+            prometheus::Opts::new("watcher_webhook_failures_total", "Deliveries that failed after every retry"),
+            &["endpoint"]
+        ).expect("Invalid metric"),
+        files_watched: prometheus::IntGauge::new("watcher_files_watched", "Files currently watched")
+            .expect("Invalid metric"),
+        poll_duration: prometheus::Histogram::with_opts(
+            prometheus::HistogramOpts::new("watcher_poll_duration_seconds", "Time taken to sync the trees with the disk")
+        ).expect("Invalid metric"),
+        slow_cycles: prometheus::IntCounter::new("watcher_slow_cycles_total", "Polls that took longer than the poll interval")
+            .expect("Invalid metric")
+    };
+
+    let _ = metrics.registry.register(Box::new(metrics.notifications.clone()));
+    let _ = metrics.registry.register(Box::new(metrics.webhook_failures.clone()));
+    let _ = metrics.registry.register(Box::new(metrics.files_watched.clone()));
+    let _ = metrics.registry.register(Box::new(metrics.poll_duration.clone()));
+    let _ = metrics.registry.register(Box::new(metrics.slow_cycles.clone()));
+    metrics
+});
+
+impl Metrics {
+    /// Everything registered, in the Prometheus text format
+    fn text(&self) -> String {
+        prometheus::TextEncoder::new().encode_to_string(&self.registry.gather()).unwrap_or_else(|e| {
+            error!(error = %e, "failed to encode the metrics");
+            String::new()
+        })
+    }
+}
+
+/// Serve the routes on the address until the process exits
+async fn serve(addr: &str, app: axum::Router, what: &str) {
+    let listener = match tokio::net::TcpListener::bind(addr).await {
+        Ok(t) => t,
+        Err(e) => {
+            error!(addr, error = %e, "cannot serve the {}", what);
+            return
+        }
+    };
+
+    info!(addr, "serving the {}", what);
+    if let Err(e) = axum::serve(listener, app).await {
+        error!(addr, error = %e, "{} server stopped", what);
+    }
+}
+
+/// Serve the health check and the status of the watcher
+pub async fn serve_status(addr: String) {
+    let app = axum::Router::new()
+        .route("/healthz", axum::routing::get(|| async { "ok" }))
+        .route("/status", axum::routing::get(|| async { axum::Json(STATUS.json()) }));
+
+    serve(&addr, app, "status").await;
+}
+
+/// Notifications in JSON, for the clients of the event stream
+static EVENTS: Lazy<tokio::sync::broadcast::Sender<String>> = Lazy::new(|| {
+    tokio::sync::broadcast::channel(256).0
+});
+
+/// Post a heartbeat to every endpoint taking JSON on each interval, whether
+/// anything changed or not, until the watcher stops. The ones set up for
+/// `unified` or `simple` bodies are left out rather than sent JSON they don't
+/// expect. Like the notifications about a whole root, heartbeats aren't about
+/// a file, so an endpoint's `targets` don't hold them back
+pub async fn heartbeat(every: Duration) {
+    let mut interval = tokio::time::interval(every);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    interval.tick().await;
+
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = shutdown_requested() => break
+        }
+
+        let body = serde_json::json!({
+            "type": "heartbeat",
+            "time": DateTime::<Utc>::from(SystemTime::now()).to_rfc3339(),
+            "source": *SOURCE.get(),
+            "files_watched": STATUS.watched_files.load(Ordering::Relaxed)
+        }).to_string();
+
+        for endpoint in CONFIG.load().endpoints.iter().filter(|endpoint| endpoint.format == "json") {
+            if CONFIG.load().dry_run {
+                info!(endpoint = endpoint.url, body, "dry run, not sending the heartbeat");
+                continue;
+            }
+
+            if let Err(reason) = deliver(&CLIENT.get(), &endpoint.url, &body, "application/json").await {
+                warn!(endpoint = endpoint.url, reason, "failed to send the heartbeat");
+            }
+        }
+    }
+}
+
+/// Stream every notification emitted from then on to each client of `/events`
+pub async fn serve_events(addr: String) {
+    use axum::response::sse::{Event, KeepAlive, Sse};
+
+    let app = axum::Router::new()
+        .route("/events", axum::routing::get(|| async {
+            // Clients too slow to keep up miss the notifications they fell behind on
+            let stream = futures_util::stream::unfold(EVENTS.subscribe(), |mut rx| async move {
+                loop {
+                    match rx.recv().await {
+                        Ok(json) => return Some((Ok::<Event, std::convert::Infallible>(Event::default().data(json)), rx)),
+                        Err(tokio::sync::broadcast::error::RecvError::Lagged(missed)) => {
+                            warn!(missed, "event stream client fell behind");
+                        },
+                        Err(tokio::sync::broadcast::error::RecvError::Closed) => return None
+                    }
+                }
+            });
+
+            Sse::new(stream).keep_alive(KeepAlive::default())
+        }));
+
+    serve(&addr, app, "events").await;
+}
+
+/// Serve the metrics of the watcher for Prometheus to scrape
+pub async fn serve_metrics(addr: String) {
+    let app = axum::Router::new()
+        .route("/metrics", axum::routing::get(|| async {
+            ([(axum::http::header::CONTENT_TYPE, prometheus::TEXT_FORMAT)], METRICS.text())
+        }));
+
+    serve(&addr, app, "metrics").await;
+}
+
+/// Environment `on_change_command` is run with for a notification
+fn command_env(notif: &Notification) -> Vec<(&'static str, String)> {
+    let time: DateTime<Utc> = notif.time.into();
+    vec![
+        ("WATCHER_PATH", notif.path.to_string_lossy().to_string()),
+        ("WATCHER_KIND", notif.kind.as_str().to_string()),
+        ("WATCHER_TIME", time.to_rfc3339()),
+        ("WATCHER_SEQ", notif.seq.to_string()),
+        ("WATCHER_DIFF", notif.unified_diff())
+    ]
+}
+
+/// Run the command for the notifications coming in, one run at a time. The
+/// ones that arrive while it runs or within the debounce are collapsed into
+/// a single run for the last of them
+async fn run_command(command: String, mut rx: tokio::sync::mpsc::UnboundedReceiver<Vec<(&'static str, String)>>) {
+    let debounce = Duration::from_millis(CONFIG.load().on_change_debounce_ms);
+
+    while let Some(mut vars) = rx.recv().await {
+        let mut collapsed = 0;
+        while !debounce.is_zero() {
+            match tokio::time::timeout(debounce, rx.recv()).await {
+                Ok(Some(next)) => {
+                    vars = next;
+                    collapsed += 1;
+                },
+                _ => break
+            }
+        }
+
+        let mut process = if cfg!(windows) {
+            let mut process = tokio::process::Command::new("cmd");
+            process.arg("/C");
+            process
+        } else {
+            let mut process = tokio::process::Command::new("sh");
+            process.arg("-c");
+            process
+        };
+
+        debug!(command, collapsed, "running the change command");
+        match process.arg(&command).envs(vars).kill_on_drop(true).output().await {
+            Ok(output) => {
+                let stdout = String::from_utf8_lossy(&output.stdout);
+                let stderr = String::from_utf8_lossy(&output.stderr);
+                if output.status.success() {
+                    info!(command, stdout = %stdout.trim_end(), stderr = %stderr.trim_end(), "change command done");
+                } else {
+                    warn!(command, status = %output.status, stdout = %stdout.trim_end(), stderr = %stderr.trim_end(), "change command failed");
+                }
+            },
+            Err(e) => error!(command, error = %e, "failed to run the change command")
+        }
+    }
+}
+
+/// Flipped to true once the watcher has been asked to stop
+static SHUTDOWN: Lazy<watch::Sender<bool>> = Lazy::new(|| watch::channel(false).0);
+
+/// Resolves once a shutdown has been requested
+async fn shutdown_requested() {
+    let mut rx = SHUTDOWN.subscribe();
+    let _ = rx.wait_for(|stop| *stop).await;
+}
+
+/// Ask every running watcher to stop, once the notifications it already
+/// found have been handed over
+pub fn shutdown() {
+    SHUTDOWN.send_replace(true);
+}
+
+/// Scan the roots once, reading every file, and report how they differ from
+/// the saved state. Nothing is watched, and the state is left as it is
+pub fn check(config: Config) -> Result<Vec<Notification>, WatcherError> {
+    let trees = scan_once(config)?;
+    let state = State::load().ok_or_else(|| WatcherError::NoState(State::path()))?;
+
+    let mut drift = Vec::new();
+    for mut ft in trees {
+        ft.restore(&state, &mut drift);
+    }
+
+    pair_renames(&mut drift);
+    drift.sort_by(|a, b| a.path.cmp(&b.path));
+    for (seq, notif) in drift.iter_mut().enumerate() {
+        notif.seq = seq as u64;
+    }
+    Ok(drift)
+}
+
+/// Scan the roots once and save them as the state `check` compares against,
+/// returning how many files it holds
+pub fn save_state(config: Config) -> Result<usize, WatcherError> {
+    let mut state = State::default();
+    for ft in scan_once(config)? {
+        ft.head.record(&mut state.files, true);
+    }
+
+    let files = state.files.len();
+    state.save()?;
+    Ok(files)
+}
+
+/// Check the config given by the caller, the current directory standing
+/// in for the roots when there are none
+fn prepare(config: &mut Config) -> Result<(), WatcherError> {
+    config.validate().map_err(ConfigError::Invalid)?;
+    if config.roots.is_empty() {
+        config.roots.push(env::current_dir().map_err(WatcherError::CurrentDir)?);
+    }
+    Ok(())
+}
+
+/// The trees of the roots with the contents of every file read
+fn scan_once(mut config: Config) -> Result<Vec<FileTree>, WatcherError> {
+    prepare(&mut config)?;
+    config.lazy_content = false;
+    settle(config)?;
+
+    let mut trees: Vec<FileTree> = CONFIG.load().roots.iter().map(|root| {
+        let mut ft = FileTree::new(root);
+        ft.fill();
+        ft
+    }).collect();
+    cap_files(&mut trees);
+    Ok(trees)
+}
+
+/// Where `Watcher::notifications` reads the notifications from
+enum Stream {
+    Block(tokio::sync::mpsc::Receiver<Notification>),
+    DropOldest(Arc<Backlog>)
+}
+
+impl Stream {
+    async fn recv(&mut self) -> Option<Notification> {
+        match self {
+            Stream::Block(rx) => rx.recv().await,
+            Stream::DropOldest(backlog) => backlog.pop().await
+        }
+    }
+}
+
+/// The notifications not read yet, dropping the oldest beyond `stream_buffer`
+#[derive(Default)]
+struct Backlog {
+    queue: std::sync::Mutex<VecDeque<Notification>>,
+    added: tokio::sync::Notify,
+    closed: AtomicBool
+}
+
+impl Backlog {
+    fn push(&self, notif: Notification) {
+        let mut queue = self.queue.lock().unwrap();
+        queue.push_back(notif);
+        if queue.len() > CONFIG.load().stream_buffer {
+            queue.pop_front();
+            warn!("notification stream full, dropped the oldest notification");
+        }
+        drop(queue);
+        self.added.notify_one();
+    }
+
+    fn close(&self) {
+        self.closed.store(true, Ordering::Release);
+        self.added.notify_one();
+    }
+
+    async fn pop(&self) -> Option<Notification> {
+        loop {
+            if let Some(notif) = self.queue.lock().unwrap().pop_front() {
+                return Some(notif);
+            }
+            if self.closed.load(Ordering::Acquire) {
+                return None;
+            }
+            self.added.notified().await;
+        }
+    }
+}
+
+/// Stops the watcher running behind a stream once the stream is dropped
+struct StopOnDrop(tokio::task::JoinHandle<Result<(), WatcherError>>);
+
+impl Drop for StopOnDrop {
+    fn drop(&mut self) {
+        self.0.abort();
+    }
+}
+
+type SyncHook = Box<dyn FnMut(&[FileTree]) + Send>;
+
+/// Watches the configured roots, keeping a live tree of each in memory
+pub struct Watcher {
+    trees: Vec<FileTree>,
+    /// Called with the trees whenever they've been brought up to date
+    on_sync: Option<SyncHook>,
+    /// Reported before anything else: what's being watched, and either every
+    /// file or the changes made while the watcher wasn't running
+    pending: Vec<Notification>
+}
+
+impl Watcher {
+    /// Scan the roots of the config, failing when it's invalid. There's one
+    /// config per process, so the watchers after the first have to be given
+    /// the same one, or the one `reload` put in effect since
+    pub fn new(mut config: Config) -> Result<Watcher, WatcherError> {
+        prepare(&mut config)?;
+        settle(config)?;
+
+        let mut trees: Vec<FileTree> = CONFIG.load().roots.iter().map(|root| {
+            let mut ft = FileTree::new(root);
+            ft.fill();
+            ft
+        }).collect();
+        cap_files(&mut trees);
+
+        let mut pending: Vec<Notification> = Vec::new();
+        if CONFIG.load().notify_on_start {
+            for ft in &trees {
+                let mut files: Vec<PathBuf> = Vec::new();
+                ft.head.file_paths(&mut files);
+                files.sort();
+                pending.push(Notification::new(&ft.root, NotificationKind::Started { files }));
+            }
+        }
+
+        if CONFIG.load().emit_initial_snapshot {
+            for ft in &trees {
+                ft.head.notify_creation(&mut pending);
+            }
+        } else if let Some(state) = CONFIG.load().persist_state.then(State::load).flatten() {
+            for ft in &mut trees {
+                ft.restore(&state, &mut pending);
+            }
+        }
+
+        Ok(Watcher { trees, on_sync: None, pending })
+    }
+
+    /// Print the tree of watched files under every root
+    pub fn display(&self) {
+        for ft in &self.trees {
+            ft.display();
+        }
+    }
+
+    /// Call the hook with the trees every time they've been synced with the
+    /// disk, e.g. to show them live
+    pub fn on_sync<G: FnMut(&[FileTree]) + Send + 'static>(mut self, hook: G) -> Watcher {
+        self.on_sync = Some(Box::new(hook));
+        self
+    }
+
+    /// Watch in the background, yielding the notifications in the order the
+    /// changes were detected. Up to `stream_buffer` of them wait to be read,
+    /// then the watcher either waits for room or drops the oldest, by
+    /// `stream_backpressure`. The stream ends once a shutdown is requested, and dropping it stops
+    /// the watcher
+    pub fn notifications(self) -> impl futures_util::Stream<Item = Notification> + Send {
+        let (receiver, task) = if CONFIG.load().stream_backpressure == "drop_oldest" {
+            let backlog = Arc::new(Backlog::default());
+            let sender = backlog.clone();
+            let task = tokio::spawn(async move {
+                let result = self.watch(|notif| sender.push(notif)).await;
+                sender.close();
+                result
+            });
+            (Stream::DropOldest(backlog), task)
+        } else {
+            let (tx, rx) = tokio::sync::mpsc::channel(CONFIG.load().stream_buffer);
+            let task = tokio::spawn(self.run(move |notif| {
+                let tx = tx.clone();
+                async move {
+                    // The stream being gone stops the watcher anyway
+                    let _ = tx.send(notif).await;
+                }
+            }));
+            (Stream::Block(rx), task)
+        };
+
+        let task = StopOnDrop(task);
+        futures_util::stream::unfold((receiver, task), |(mut receiver, mut task)| async move {
+            match receiver.recv().await {
+                Some(notif) => Some((notif, (receiver, task))),
+                None => {
+                    if let Ok(Err(e)) = (&mut task.0).await {
+                        error!(error = %e, "stopped watching");
+                    }
+                    None
+                }
+            }
+        })
+    }
+
+    /// Watch until a shutdown is requested, calling back once per
+    /// notification in the order the changes were detected. Fails when a root
+    /// is lost with `exit_on_root_lost`
+    pub async fn watch<F: FnMut(Notification)>(self, mut callback: F) -> Result<(), WatcherError> {
+        self.run(move |notif| {
+            callback(notif);
+            std::future::ready(())
+        }).await
+    }
+
+    /// Watch like `watch`, waiting for the callback to be done with each
+    /// notification before handing it the next one
+    async fn run<F, Fut>(mut self, mut callback: F) -> Result<(), WatcherError>
+    where F: FnMut(Notification) -> Fut, Fut: std::future::Future<Output = ()> {
+        let mut hook = self.on_sync.take();
+        let synced = |trees: &[FileTree]| {
+            STATUS.record_poll(trees);
+            if let Some(hook) = &mut hook {
+                hook(trees);
+            }
+        };
+
+        let config = CONFIG.load();
+        if config.startup_jitter_ms > 0 {
+            let jitter = Duration::from_millis(rand::random_range(0..=config.startup_jitter_ms));
+            info!(jitter_ms = jitter.as_millis() as u64, "delaying the first poll");
+            tokio::select! {
+                _ = tokio::time::sleep(jitter) => {},
+                _ = shutdown_requested() => {}
+            }
+        }
+
+        let (mut command_tx, mut command) = (None, None);
+        if let Some(line) = config.on_change_command.clone() {
+            let (tx, rx) = tokio::sync::mpsc::unbounded_channel();
+            command_tx = Some(tx);
+            command = Some(tokio::spawn(run_command(line, rx)));
+        }
+
+        let callback = move |notif: Notification| {
+            if let Some(tx) = &command_tx {
+                let _ = tx.send(command_env(&notif));
+            }
+            callback(notif)
+        };
+
+        let result = match CONFIG.load().backend.as_str() {
+            "events" => watch_events(&mut self.trees, self.pending, callback, synced).await,
+            _ => watch_poll(&mut self.trees, self.pending, callback, synced).await
+        };
+
+        // The callback owned the sender, so the command stops after its last run
+        if let Some(command) = command {
+            let _ = command.await;
+        }
+
+        if CONFIG.load().persist_state {
+            let mut state = State::default();
+            for ft in &self.trees {
+                ft.head.record(&mut state.files, true);
+            }
+            if let Err(e) = state.save() {
+                warn!(error = %e, "failed to save the state");
+            }
+        }
+
+        result
+    }
+}
+
+/* Note on slow polls:
+ *
+ * Filling a large tree can take longer than the poll interval. Changes aren't
+ * missed when that happens: a file counts as modified whenever its modification
+ * time differs from the one last seen, however long ago that was, rather than
+ * when it falls within the last interval. They're only reported late.
+ *
+ * Cycles longer than the interval are logged, and counted in `/status` and in
+ * `watcher_slow_cycles_total`, to tell when the watcher isn't keeping up.
+ */
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A modification of a file from one version to the other, diffed with the defaults
+    fn modified(old: &str, new: &str) -> Notification {
+        let mut notif = Notification::new(Path::new("/project/a.txt"), NotificationKind::Modified);
+        let old: Vec<&str> = old.lines().collect();
+        let new: Vec<&str> = new.lines().collect();
+        notif.diff = diff_lines(&old, &new);
+        notif
+    }
+
+    /// The numbered lines of a file, `1` to `count`
+    fn numbered(count: usize) -> Vec<String> {
+        (1..=count).map(|n| n.to_string()).collect()
+    }
+
+    #[test]
+    fn unified_hunk_spans_the_context_around_a_change() {
+        let old = numbered(10);
+        let mut new = old.clone();
+        new[4] = "five".to_string();
+
+        let unified = modified(&old.join("\n"), &new.join("\n")).unified_diff();
+        let lines: Vec<&str> = unified.lines().collect();
+
+        assert_eq!(lines[2], "@@ -2,7 +2,7 @@");
+        assert_eq!(&lines[3..], [" 2", " 3", " 4", "-5", "+five", " 6", " 7", " 8"]);
+    }
+
+    #[test]
+    fn unified_hunks_merge_when_their_context_overlaps() {
+        let old = numbered(30);
+        let mut new = old.clone();
+        new[4] = "five".to_string();
+        new[10] = "eleven".to_string();
+        new[25] = "twenty-six".to_string();
+
+        let unified = modified(&old.join("\n"), &new.join("\n")).unified_diff();
+        let headers: Vec<&str> = unified.lines().filter(|line| line.starts_with("@@")).collect();
+
+        // Lines 5 and 11 are six apart, within twice the context of each other
+        assert_eq!(headers, ["@@ -2,13 +2,13 @@", "@@ -23,7 +23,7 @@"]);
+    }
+
+    #[test]
+    fn unified_empty_ranges_point_at_the_line_before() {
+        let mut notif = Notification::new(Path::new("/project/new.txt"), NotificationKind::Created);
+        notif.diff = vec![diff::Result::Right("a".to_string()), diff::Result::Right("b".to_string())];
+
+        let unified = notif.unified_diff();
+        assert!(unified.starts_with("--- /dev/null\n+++ /project/new.txt\n@@ -0,0 +1,2 @@\n+a\n+b\n"));
+    }
+
+    #[test]
+    fn context_lines_surround_every_change() {
+        let old = numbered(12);
+        let mut new = old.clone();
+        new[0] = "one".to_string();
+
+        let notif = modified(&old.join("\n"), &new.join("\n"));
+        let near: Vec<usize> = notif.near_changes().iter().enumerate()
+            .filter(|(_, near)| **near).map(|(index, _)| index).collect();
+
+        // The removed and the added line, then three unchanged ones after them
+        assert_eq!(near, [0, 1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn saved_state_round_trips_with_contents() {
+        let mut state = State { version: State::VERSION, files: BTreeMap::new() };
+        state.files.insert(PathBuf::from("/root/a.txt"), FileState {
+            mtime: Some(1_700_000_000_000),
+            hash: Some("00ff".to_string()),
+            size: Some(12),
+            content: Some("one\ntwo\n".to_string())
+        });
+        state.files.insert(PathBuf::from("/root/large.bin"), FileState {
+            mtime: Some(1_700_000_000_001),
+            hash: None,
+            size: Some(1 << 30),
+            content: None
+        });
+
+        let json = serde_json::to_string(&state).unwrap();
+        let loaded: State = serde_json::from_str(&json).unwrap();
+
+        assert_eq!(loaded.version, State::VERSION);
+        assert!(loaded.files == state.files);
+        assert!(!json.contains("\"content\":null"));
+    }
+
+    #[test]
+    fn saved_state_without_contents_still_loads() {
+        let json = r#"{"version":1,"files":{"/root/a.txt":{"mtime":5,"hash":"00ff","size":3}}}"#;
+        let loaded: State = serde_json::from_str(json).unwrap();
+
+        let file = &loaded.files[Path::new("/root/a.txt")];
+        assert_eq!(file.mtime, Some(5));
+        assert_eq!(file.content, None);
+    }
+
+    #[test]
+    fn tokenize_keeps_words_together_and_splits_punctuation() {
+        assert_eq!(
+            Notification::tokenize("foo_bar(x, y)"),
+            ["foo_bar", "(", "x", ",", " ", "y", ")"]
+        );
+        assert_eq!(Notification::tokenize("a  b->c"), ["a", "  ", "b", "-", ">", "c"]);
+        assert!(Notification::tokenize("").is_empty());
+    }
+
+    #[test]
+    fn word_diff_marks_only_the_changed_tokens() {
+        assert_eq!(Notification::word_diff("let x = 1;", "let y = 1;", false), "let [-x-]{+y+} = 1;");
+        assert_eq!(Notification::word_diff("a b", "a b c", false), "a b{+ c+}");
+        assert_eq!(Notification::word_diff("same", "same", false), "same");
+    }
+
+    /// A notification for the given path carrying the hash of its contents
+    fn hashed(path: &str, kind: NotificationKind, hash: Option<&str>) -> Notification {
+        let mut notif = Notification::new(Path::new(path), kind);
+        notif.hash = hash.map(String::from);
+        notif
+    }
+
+    #[test]
+    fn renames_pair_a_deletion_with_a_creation_of_the_same_contents() {
+        let mut buffer = vec![
+            hashed("/project/old.txt", NotificationKind::Deleted, Some("abc")),
+            hashed("/project/new.txt", NotificationKind::Created, Some("abc"))
+        ];
+        pair_renames(&mut buffer);
+
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer[0].path, Path::new("/project/new.txt"));
+        match &buffer[0].kind {
+            NotificationKind::Renamed { from, to } => {
+                assert_eq!(from, Path::new("/project/old.txt"));
+                assert_eq!(to, Path::new("/project/new.txt"));
+            }
+            _ => panic!("expected a rename")
+        }
+    }
+
+    #[test]
+    fn renames_leave_different_or_unhashed_contents_alone() {
+        let mut buffer = vec![
+            hashed("/project/old.txt", NotificationKind::Deleted, Some("abc")),
+            hashed("/project/new.txt", NotificationKind::Created, Some("def")),
+            hashed("/project/gone.txt", NotificationKind::Deleted, None),
+            hashed("/project/fresh.txt", NotificationKind::Created, None)
+        ];
+        pair_renames(&mut buffer);
+
+        assert_eq!(buffer.len(), 4);
+        assert!(buffer.iter().all(|notif| !matches!(notif.kind, NotificationKind::Renamed { .. })));
+    }
+
+    #[test]
+    fn rate_limiter_empties_then_refills_at_the_rate() {
+        let start = Instant::now();
+        let mut bucket = RateLimiter { tokens: 2.0, refilled: start };
+
+        assert_eq!(bucket.take(2.0, start), None);
+        assert_eq!(bucket.take(2.0, start), None);
+        assert_eq!(bucket.take(2.0, start), Some(Duration::from_millis(500)));
+
+        // A quarter of a second brings back half a token
+        let later = start + Duration::from_millis(250);
+        assert_eq!(bucket.take(2.0, later), Some(Duration::from_millis(250)));
+        assert_eq!(bucket.take(2.0, later + Duration::from_millis(250)), None);
+    }
+
+    #[test]
+    fn rate_limiter_holds_at_most_a_second_of_tokens() {
+        let start = Instant::now();
+        let mut bucket = RateLimiter { tokens: 0.0, refilled: start };
+
+        let later = start + Duration::from_secs(10);
+        for _ in 0..3 {
+            assert_eq!(bucket.take(3.0, later), None);
+        }
+        assert!(bucket.take(3.0, later).is_some());
+    }
+
+    #[test]
+    fn endpoints_are_urls_or_tables() {
+        let config: Config = toml::from_str(r#"
+            endpoints = [
+                "https://example.com/hook",
+                { url = "wss://example.com/feed", format = "unified", targets = ["rs", "*.toml"] },
+                { url = "unix:/run/watcher.sock" }
+            ]
+        "#).unwrap();
+
+        let endpoints = &config.endpoints;
+        assert_eq!(endpoints.len(), 3);
+
+        assert_eq!(endpoints[0].url, "https://example.com/hook");
+        assert_eq!(endpoints[0].format, "json");
+        assert!(endpoints[0].targets.is_empty());
+
+        assert_eq!(endpoints[1].url, "wss://example.com/feed");
+        assert_eq!(endpoints[1].format, "unified");
+        assert_eq!(endpoints[1].targets, ["rs", "*.toml"]);
+
+        assert_eq!(endpoints[2].url, "unix:/run/watcher.sock");
+        assert_eq!(endpoints[2].format, "json");
+    }
+
+    #[test]
+    fn endpoints_without_a_url_are_rejected() {
+        assert!(toml::from_str::<Config>(r#"endpoints = [{ format = "simple" }]"#).is_err());
+    }
+
+    #[test]
+    fn reload_turns_down_an_invalid_config() {
+        let config = Config { endpoints: Vec::new(), ..Config::default() };
+        assert!(matches!(reload(config), Err(ConfigError::Invalid(_))));
+        assert!(PENDING.lock().unwrap().is_none());
+    }
+
+    #[test]
+    fn watchers_turn_down_an_invalid_config() {
+        let config = Config { endpoints: vec![Endpoint::new("ftp://example.com")], ..Config::default() };
+        assert!(matches!(Watcher::new(config), Err(WatcherError::Config(ConfigError::Invalid(_)))));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn linked_folders_are_kept_as_leaves() {
+        let root = env::temp_dir().join(format!("watcher-test-links-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        fs::create_dir_all(root.join("real")).unwrap();
+        fs::write(root.join("real/a.txt"), "a").unwrap();
+        std::os::unix::fs::symlink(root.join("real"), root.join("linked")).unwrap();
+
+        let mut ft = FileTree::new(&root);
+        ft.fill();
+        fs::remove_dir_all(&root).unwrap();
+
+        let linked = ft.head.children.iter().find(|child| child.name == "linked").expect("the link is in the tree");
+        assert!(linked.link);
+        assert!(linked.children.is_empty());
+        assert!(ft.head.children.iter().any(|child| child.name == "real" && child.children.len() == 1));
+    }
+
+    #[test]
+    fn every_setting_is_documented() {
+        let value = serde_json::to_value(Config::default()).unwrap();
+        let keys: std::collections::BTreeSet<&str> = value.as_object().unwrap().keys().map(String::as_str).collect();
+        let documented: std::collections::BTreeSet<&str> = CONFIG_DOCS.iter().map(|(key, _, _)| *key).collect();
+
+        assert_eq!(documented.len(), CONFIG_DOCS.len(), "a setting is documented twice");
+        assert_eq!(keys, documented);
+    }
+
+    #[test]
+    fn content_hashing_only_reports_new_contents() {
+        let config: Config = toml::from_str("use_content_hash = true").unwrap();
+        assert!(config.use_content_hash);
+
+        let mut node = Node::new();
+        node.path = PathBuf::from("/project/a.txt");
+        node.kind = NodeType::File;
+        node.content = Some("one\n".to_string());
+        node.hash = Some(Node::hash("one\n"));
+
+        // Touched without being changed
+        assert!(!node.rehash(Some("one\n")));
+
+        assert!(node.rehash(Some("two\n")));
+        assert_eq!(node.hash, Some(Node::hash("two\n")));
+
+        let mut buffer = Vec::new();
+        node.notify_change(Some("two\n".to_string()), &mut buffer);
+        assert_eq!(buffer.len(), 1);
+        assert_eq!(buffer[0].diff.len(), 2);
+    }
+
+    #[test]
+    fn targets_are_globs_or_bare_extensions() {
+        let config: Config = toml::from_str(r#"targets = ["rs", "Dockerfile", "*.test.js", "Cargo.*"]"#).unwrap();
+        let targets = build_targets(&config.targets, false);
+
+        for name in ["main.rs", "Dockerfile", "app.test.js", "Cargo.toml", "Cargo.lock"] {
+            assert!(targets.is_match(name), "{} should be watched", name);
+        }
+        for name in ["app.js", "rs.txt", "dockerfile", "Makefile"] {
+            assert!(!targets.is_match(name), "{} shouldn't be watched", name);
+        }
+
+        assert!(build_targets(&config.targets, true).is_match("DOCKERFILE"));
+    }
+
+    #[test]
+    fn excluded_dirs_win_over_included_ones() {
+        let config: Config = toml::from_str(r#"
+            include_dirs = ["src", "./docs"]
+            exclude_dirs = ["src/generated"]
+        "#).unwrap();
+        let in_scope = |path: &str, is_dir: bool| in_dirs(Path::new(path), is_dir, &config.include_dirs, &config.exclude_dirs);
+
+        assert!(in_scope("src/main.rs", false));
+        assert!(in_scope("docs/guide/intro.txt", false));
+        assert!(!in_scope("src/generated/schema.rs", false));
+        assert!(!in_scope("tests/it.rs", false));
+        assert!(!in_scope("build.rs", false));
+
+        // The folders leading to an included one are walked through
+        let nested = ["src/app".to_string()];
+        assert!(in_dirs(Path::new("src"), true, &nested, &[]));
+        assert!(!in_dirs(Path::new("src/lib.rs"), false, &nested, &[]));
+
+        assert!(in_dirs(Path::new("anything.rs"), false, &[], &[]));
+    }
+
+    #[test]
+    fn excluded_files_are_found_under_every_root() {
+        let config: Config = toml::from_str(r#"exclude_files = ["./db.sqlite-journal", "data\\cache.json", "/var/log/app.log"]"#).unwrap();
+        let roots = [PathBuf::from("/project"), PathBuf::from("/shared")];
+        let excluded = excluded_paths(&config.exclude_files, &roots);
+
+        for path in ["/project/db.sqlite-journal", "/shared/db.sqlite-journal", "/project/data/cache.json", "/var/log/app.log"] {
+            assert!(excluded.contains(Path::new(path)), "{} should be excluded", path);
+        }
+        assert_eq!(excluded.len(), 5);
+    }
+
+    #[test]
+    fn long_diffs_end_with_how_many_lines_were_left_out() {
+        let config: Config = toml::from_str("max_diff_lines = 2").unwrap();
+        let old = numbered(5);
+        let new: Vec<String> = old.iter().map(|line| format!("{}!", line)).collect();
+        let unified = modified(&old.join("\n"), &new.join("\n")).unified_diff();
+
+        let truncated = Notification::truncate(unified.clone(), 2, config.max_diff_lines);
+        let lines: Vec<&str> = truncated.lines().collect();
+        assert_eq!(lines.len(), 5);
+        assert_eq!(lines[2], "@@ -1,5 +1,5 @@");
+        assert_eq!(lines[4], "... (diff truncated, 9 more lines)");
+
+        assert_eq!(Notification::truncate(unified.clone(), 2, 0), unified);
+        assert_eq!(Notification::truncate(unified.clone(), 2, 11), unified);
+
+        // Without a limit the JSON holds the whole diff
+        let value = modified("a\nb", "a\nc").value();
+        assert_eq!(value["truncated"], false);
+        assert_eq!(value["total_lines"], 3);
+    }
+
+    #[test]
+    fn semantic_diffs_list_the_keys_that_changed() {
+        let config: Config = toml::from_str("semantic_diff = true").unwrap();
+        assert!(config.semantic_diff);
+
+        let changes = key_changes(
+            Path::new("/project/settings.json"),
+            r#"{"a": {"b": 1}, "items": [1, 2], "gone": true}"#,
+            r#"{"a": {"b": 2}, "items": [1, 2, 3], "added": "x"}"#
+        ).unwrap();
+        let lines: Vec<String> = changes.iter().map(KeyChange::line).collect();
+        assert_eq!(lines, ["~ a.b: 1 -> 2", "+ added: \"x\"", "- gone: true", "+ items[2]: 3"]);
+
+        let toml = key_changes(Path::new("/project/Cargo.toml"), "[package]\nversion = \"1.0\"", "[package]\nversion = \"1.1\"").unwrap();
+        assert_eq!(toml.iter().map(KeyChange::line).collect::<Vec<String>>(), ["~ package.version: \"1.0\" -> \"1.1\""]);
+
+        // Reformatting changes lines but none of the keys
+        assert!(key_changes(Path::new("/project/a.json"), r#"{"a":1}"#, "{\n  \"a\": 1\n}").unwrap().is_empty());
+
+        // Back to the line diff when either version doesn't parse, or for other files
+        assert!(key_changes(Path::new("/project/a.json"), r#"{"a": 1}"#, r#"{"a": "#).is_none());
+        assert!(key_changes(Path::new("/project/a.txt"), "1", "2").is_none());
+
+        let mut notif = modified("{\"a\": 1}", "{\"a\": 2}");
+        notif.keys = key_changes(Path::new("/project/a.json"), "{\"a\": 1}", "{\"a\": 2}");
+        assert_eq!(notif.value()["keys"], serde_json::json!([{ "path": "a", "old": 1, "new": 2 }]));
+    }
+
+    #[test]
+    fn paths_are_written_relative_to_a_single_root() {
+        assert!(Config::default().relative_paths);
+        let config: Config = toml::from_str("relative_paths = false").unwrap();
+        assert!(!config.relative_paths);
+
+        let root = [PathBuf::from("/home/user/project")];
+        let file = Path::new("/home/user/project/src/main.rs");
+        assert_eq!(shown_under(file, &root, true), Path::new("src").join("main.rs").to_string_lossy());
+        assert_eq!(shown_under(file, &root, false), "/home/user/project/src/main.rs");
+
+        // The root itself, and the paths when several roots could hold them
+        assert_eq!(shown_under(&root[0], &root, true), "/home/user/project");
+        let roots = [root[0].clone(), PathBuf::from("/home/user/shared")];
+        assert_eq!(shown_under(file, &roots, true), "/home/user/project/src/main.rs");
+    }
+
+    #[test]
+    fn the_file_cap_keeps_the_first_files_in_path_order() {
+        let config: Config = toml::from_str("max_watched_files = 2").unwrap();
+        assert_eq!(config.max_watched_files, Some(2));
+
+        let root = env::temp_dir().join(format!("watcher-test-cap-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&root);
+        for file in ["b/c.txt", "a/b.txt", "a/a.txt"] {
+            fs::create_dir_all(root.join(file).parent().unwrap()).unwrap();
+            fs::write(root.join(file), file).unwrap();
+        }
+
+        let mut ft = FileTree::new(&root);
+        ft.fill();
+        fs::remove_dir_all(&root).unwrap();
+
+        let mut files = Vec::new();
+        ft.head.file_paths(&mut files);
+        let left_out = past_room(&mut files, config.max_watched_files.unwrap());
+        assert_eq!(files, [root.join("a/a.txt"), root.join("a/b.txt")]);
+        assert_eq!(left_out, HashSet::from([root.join("b/c.txt")]));
+
+        // The folder left without files goes along with them
+        ft.head.drop_files(&left_out);
+        let names: Vec<&str> = ft.head.children.iter().map(|child| child.name.as_str()).collect();
+        assert_eq!(names, ["a"]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn sinks_get_the_notifications_of_their_files_through_rotations() {
+        let dir = env::temp_dir().join(format!("watcher-test-sinks-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+
+        let config: Config = toml::from_str(&format!(r#"
+            file_sinks = [
+                {{ pattern = "rs", path = "{0}/rust.log" }},
+                {{ pattern = "*.{{yml,yaml}}", path = "{0}/config.log" }},
+            ]
+        "#, dir.display())).unwrap();
+        config.validate().unwrap();
+
+        let notif = Notification::new(Path::new("/project/src/main.rs"), NotificationKind::Modified);
+        write_to(&config.file_sinks, &notif);
+
+        // Moved away like by logrotate, the next line goes to a new file
+        fs::rename(dir.join("rust.log"), dir.join("rust.log.1")).unwrap();
+        write_to(&config.file_sinks, &notif);
+
+        let rotated = fs::read_to_string(dir.join("rust.log.1")).unwrap();
+        let current = fs::read_to_string(dir.join("rust.log")).unwrap();
+        let config_log = dir.join("config.log").exists();
+        fs::remove_dir_all(&dir).unwrap();
+
+        assert_eq!(rotated.lines().count(), 1);
+        assert_eq!(current.lines().count(), 1);
+        let line: serde_json::Value = serde_json::from_str(current.trim_end()).unwrap();
+        assert_eq!(line["path"], "/project/src/main.rs");
+        assert!(!config_log);
+
+        let empty: Config = toml::from_str(r#"file_sinks = [{ pattern = "", path = "all.log" }]"#).unwrap();
+        assert!(empty.validate().unwrap_err().starts_with("file_sinks:"));
+    }
+}
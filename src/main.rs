@@ -1,423 +1,484 @@
-use std::{env, error::Error, fs, io::Read, os::unix::fs::MetadataExt, path::{Path, PathBuf}, thread::sleep, time::{Duration, Instant, SystemTime}};
-use serde::{Deserialize, Serialize};
-use once_cell::sync::Lazy;
-use std::time::UNIX_EPOCH;
-use chrono::{DateTime, Utc, Local};
-
-static TIMEPERIOD: u32 = 1000000000;
-
-#[derive(Serialize, Deserialize)]
-struct Config {
-    targets: Vec<String>,
-    endpoints: Vec<String>
+use std::{env, io::{BufRead, IsTerminal, Write}, path::{Path, PathBuf}, sync::Arc, time::Duration};
+use clap::{Parser, Subcommand};
+use tokio::{sync::{mpsc, watch, Semaphore}, task::JoinSet};
+use tracing::{debug, error, info, warn, Level};
+use watcher::{Config, FileTree, Notification, Watcher};
+
+mod tui;
+
+#[derive(Parser, Clone)]
+#[command(version, about)]
+struct Cli {
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Directories or files to watch, defaults to the `roots` of the config or the current directory
+    paths: Vec<PathBuf>,
+
+    /// Config file to use instead of `watcher.toml` in the current directory
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Print the tree of watched files once and exit instead of watching
+    #[arg(long, visible_alias = "scan")]
+    once: bool,
+
+    /// Log the endpoints and payloads notifications would be sent with instead of sending them
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Show the files and the changes live in a terminal UI
+    #[arg(long, conflicts_with = "once")]
+    tui: bool,
+
+    /// Watch hidden files and folders too, like dotfiles
+    #[arg(long)]
+    watch_hidden: bool,
+
+    /// Wait for the directories to watch to be created when they don't exist yet
+    #[arg(long)]
+    follow: bool,
+
+    /// Print nothing but errors, the notifications are still delivered
+    #[arg(short, long, conflicts_with = "tui")]
+    quiet: bool,
+
+    /// Log more details to stderr, repeat for more (-v info, -vv debug, -vvv trace)
+    #[arg(short, long, action = clap::ArgAction::Count)]
+    verbose: u8
 }
 
-impl Config {
-    /// Get the path to the config file 
-    fn get_path() -> PathBuf { 
-        let cwd = env::current_dir().expect("Error retrieving current working directory");
-        cwd.join("watcher.toml")
+#[derive(Subcommand, Clone)]
+enum Command {
+    /// Write the config file with the defaults, explaining every setting in TOML
+    Init {
+        /// Overwrite the config file without asking when it exists
+        #[arg(long)]
+        force: bool
+    },
+    /// Send a sample notification to every endpoint and report how each responded
+    TestEndpoints,
+    /// Compare the files with the saved state and print what differs, exiting
+    /// with 1 when anything does and 2 when the check can't be made
+    Check {
+        /// Save the files as they are now as the state to check against instead
+        #[arg(long)]
+        save: bool
     }
+}
 
-    /// Generate the default configuration
-    fn default() -> Self {
-        Config {
-            targets: vec![
-                "txt".to_string(),
-                "json".to_string(),
-                "toml".to_string(),
-                "rs".to_string(),
-            ],
-            endpoints: vec![
-                "localhost:9996".to_string()
-            ]
-        }
-    }
-
-    /// Save the config to a file 
-    fn save(&self) -> Result<(), Box<dyn Error>> {
-        let path = Config::get_path();
-        let config_str = toml::to_string(self)?;
-        fs::write(path, config_str)?;
-        Ok(())
-    }
-
-    /// Reads the config file and returns its contents as a table
-    fn fetch() -> Result<Config, Box<dyn Error>> {
-        let path = Config::get_path();
-        
-        // Try to read the config file, if it doesn't exist, create default
-        let config = match fs::read_to_string(&path) {
-            Ok(contents) => toml::from_str(&contents)?,
-            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
-                let config = Config::default();
-                config.save()?;
-                config
-            },
-            Err(e) => return Err(Box::new(e)),
-        };
-
-        Ok(config)
+/// The config file given with `--config`, `watcher.toml` in the current directory otherwise
+fn config_path(cli: &Cli) -> PathBuf {
+    match &cli.config {
+        Some(path) => path.clone(),
+        None => env::current_dir().expect("Error retrieving current working directory").join("watcher.toml")
     }
 }
 
-static CONFIG: Lazy<Config> = Lazy::new(|| {
-    Config::fetch().unwrap_or(Config::default())
-});
+/// Write the commented default config, asking before replacing an existing one
+fn init(path: &Path, force: bool) {
+    if path.exists() && !force {
+        if !std::io::stdin().is_terminal() {
+            eprintln!("{} already exists, use --force to overwrite it", path.display());
+            std::process::exit(1);
+        }
 
-#[derive(Debug)]
-enum NodeType {
-    File, 
-    Folder
-}
+        eprint!("{} already exists, overwrite it? [y/N] ", path.display());
+        let mut answer = String::new();
+        let _ = std::io::stdin().lock().read_line(&mut answer);
+        if !matches!(answer.trim(), "y" | "Y" | "yes") {
+            return;
+        }
+    }
 
-enum FileError {
-    Open,
-    Metadata,
-    TooLarge
-}
+    let result = if Config::can_comment(path) {
+        Config::default().save_commented(path)
+    } else {
+        Config::default().save(path)
+    };
 
-#[allow(dead_code)]
-struct Node {
-    kind: NodeType,
-    path: PathBuf,
-    name: String,
-    elapsed: Option<u128>,
-    children: Vec<Node>,
-    content: Option<String>,
-    modified: bool
+    if let Err(e) = result {
+        eprintln!("Cannot save config {}: {}", path.display(), e);
+        std::process::exit(1);
+    }
+    println!("Wrote {}", path.display());
 }
 
-impl Node {
-    fn new() -> Self {
-        Self {
-            kind: NodeType::Folder,
-            path: env::current_dir().expect("Failed to read current directory"),
-            name: "root".to_string(),
-            elapsed: None,
-            children: Vec::new(),
-            content: None,
-            modified: false
+/// Report how every endpoint took a sample notification, exiting with an
+/// error when any of them didn't
+async fn test_endpoints(config: Config) {
+    let checks = watcher::check_endpoints(config).await.unwrap_or_else(|e| {
+        eprintln!("Cannot test the endpoints: {}", e);
+        std::process::exit(1);
+    });
+
+    let mut failed = false;
+    for check in &checks {
+        let millis = check.latency.as_millis();
+        match &check.outcome {
+            Ok(status) => println!("ok      {} {} ({} ms)", check.endpoint, status, millis),
+            Err(reason) => {
+                failed = true;
+                println!("FAILED  {} {} ({} ms)", check.endpoint, reason, millis);
+            }
         }
     }
-    
-    fn fill(&mut self, path: &Path) {
-        self.path = path.to_path_buf();
-        if let Some(name) = path.file_name() {
-            self.name = name.to_str().unwrap().to_string();
-        }
 
-        self.kind = {
-            if path.is_dir() { 
-                NodeType::Folder 
-            } else {
-                NodeType::File
-            }
-        };
+    if failed {
+        std::process::exit(1);
+    }
+}
 
-        match self.kind {
-            NodeType::File => {
-                if path.extension().is_none() ||
-                    !CONFIG.targets.contains(&path.extension().unwrap().to_str().unwrap().to_string()) {
-                    
-                    // Indicator that the file type is invalid
-                    self.path = "...".into();
-                    return
-                }
+/// Tell whether the files drifted from the saved state, or save it, exiting
+/// with a code CI can act on
+fn check(config: Config, save: bool) {
+    let quiet = config.quiet;
 
-                self.elapsed = match path.metadata() {
-                    Ok(t) => {
-                        Some(t.modified().expect("Error retrieving metadata").duration_since(UNIX_EPOCH).unwrap().as_millis())
-                    },
-                    _ => {
-                        None
-                    }
-                };
-                
-                self.content = match self.read() {
-                    Ok(t) => Some(t),
-                    Err(_) => None
-                }
+    if save {
+        match watcher::save_state(config) {
+            Ok(files) => if !quiet {
+                println!("Saved the state of {} files", files);
             },
-            NodeType::Folder => {
-                self.elapsed = None;
-
-                for res in match path.read_dir() {
-                    Ok(t) => t,
-                    _ => return
-                } {
-                    let entry: fs::DirEntry = res.expect("Invalid Entry");
-                    let mut child: Node = Node::new();
-                    child.fill(&entry.path());
-
-                    if child.path.to_str().unwrap() == "..." {
-                        continue;
-                    }
-
-                    if matches!(child.kind, NodeType::Folder) && child.children.is_empty() {
-                        continue;
-                    }
-                    
-                    self.children.push(child);
-                }
+            Err(e) => {
+                eprintln!("Cannot save the state: {}", e);
+                std::process::exit(2);
             }
-        };
+        }
+        return;
     }
 
-    fn display(&self, prev: &str) {
-        let mut name_column = format!("{}└── {}", prev, self.name);
-        
-        const MAX_WIDTH: usize = 60;
-        const OFFSET: usize = 20;
+    let drift = watcher::check(config).unwrap_or_else(|e| {
+        eprintln!("Cannot check: {}", e);
+        std::process::exit(2);
+    });
 
-        if self.name.len() > MAX_WIDTH {
-            let mut name = self.name.clone();
-            name.truncate(MAX_WIDTH);
-            name_column = format!("{}└── {}...", prev, name);
+    if !quiet {
+        for notif in &drift {
+            notif.display();
         }
+    }
 
-        println!("{:.<width$} Last Modified: -{} millis", name_column, self.elapsed.unwrap_or(u128::MAX), width=MAX_WIDTH + OFFSET);
-
-        for child in &self.children {
-            child.display(&format!("{}│  ", prev));
-        }
+    if !drift.is_empty() {
+        eprintln!("{} change(s) since the state was saved", drift.len());
+        std::process::exit(1);
     }
+}
 
-    #[allow(dead_code)]
-    fn read(&self) -> core::result::Result<String, FileError> {
-        let mut file: fs::File = match fs::File::open(self.path.clone()) {
-            Ok(t) => t,
-            _ => return Err(FileError::Open)
-        };
+/// Load the config, either given with `--config` or the default one
+fn load_config(cli: &Cli) -> Config {
+    let path = config_path(cli);
+
+    let mut config = Config::load(&path).unwrap_or_else(|e| {
+        eprintln!("Cannot use config {}: {}", path.display(), e);
+        std::process::exit(1);
+    });
+    apply_flags(cli, &mut config);
+
+    // Canonical paths of the directories being watched, the ones given on the
+    // command line win over the ones in the config. Missing ones are waited
+    // for with `wait_for_root`, so they're left as they are until then
+    let paths = if cli.paths.is_empty() { &config.roots } else { &cli.paths };
+    config.roots = paths.iter().map(|path| {
+        path.canonicalize().unwrap_or_else(|e| {
+            if config.wait_for_root && e.kind() == std::io::ErrorKind::NotFound {
+                return path.clone();
+            }
+            eprintln!("Cannot watch {}: {}", path.display(), e);
+            std::process::exit(1);
+        })
+    }).collect();
 
-        let metadata: fs::Metadata = match file.metadata() {
-            Ok(t) => t,
-            _ => return Err(FileError::Metadata)
-        };
+    config
+}
 
-        if metadata.size() > 1024 * 1024 * 10 {
-            return Err(FileError::TooLarge)
-        }
+/// Turn on the settings the flags ask for
+fn apply_flags(cli: &Cli, config: &mut Config) {
+    config.dry_run |= cli.dry_run;
+    config.quiet |= cli.quiet;
+    config.watch_hidden |= cli.watch_hidden;
+    config.wait_for_root |= cli.follow;
+}
 
-        let mut buffer: String = String::new();
-        let _ = file.read_to_string(&mut buffer);
+/// Load the config again on every SIGHUP, keeping the one in effect when the
+/// file doesn't parse or has invalid settings
+#[cfg(unix)]
+async fn reload_on_hangup(cli: Cli) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut hangups = match signal(SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            warn!(error = %e, "cannot listen for SIGHUP, the config won't be reloaded");
+            return;
+        }
+    };
 
-        Ok(buffer)
+    while hangups.recv().await.is_some() {
+        let path = config_path(&cli);
+        let reloaded = Config::load(&path).and_then(|mut config| {
+            apply_flags(&cli, &mut config);
+            watcher::reload(config)
+        });
+        if let Err(e) = reloaded {
+            error!(path = %path.display(), error = %e, "cannot reload the config, keeping the current one");
+        }
     }
+}
 
-    fn poll(&mut self, buffer: &mut Vec<Notification>) {
-        let elapsed: Option<u128> = match self.path.metadata() {
-            Ok(t) => {
-                Some(t.modified().expect("Error retrieving metadata").duration_since(UNIX_EPOCH).unwrap().as_millis())
-            },
-            _ => {
-                None
+/// Longest wait between two looks for a root that doesn't exist yet
+const ROOT_WAIT_MAX: Duration = Duration::from_secs(10);
+
+/// Wait for every root to exist, checking again with a growing backoff, and
+/// canonicalize them once they do
+async fn wait_for_roots(roots: &mut [PathBuf]) {
+    for root in roots.iter_mut() {
+        let mut backoff = Duration::from_millis(250);
+        let mut logged = false;
+
+        let canonical = loop {
+            match root.canonicalize() {
+                Ok(canonical) => break canonical,
+                Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+                Err(e) => {
+                    eprintln!("Cannot watch {}: {}", root.display(), e);
+                    std::process::exit(1);
+                }
             }
-        };
 
-        if matches!(self.kind, NodeType::File) && self.elapsed != elapsed {
-            // change noticed
-            let mut notifs = Notification::new(&self.path);
-            let option_new_lines = match self.read() {
-                Ok(t) => Some(t),
-                _ => None
-            };
-
-            let option_old_lines = self.content.clone();
-            self.content = option_new_lines.clone();
-
-            if option_new_lines.is_some() && option_old_lines.is_some() {
-                let old_lines = option_old_lines.unwrap();
-                let new_lines = option_new_lines.unwrap();
-
-                let mut diff_output = Vec::new();
-                diff::lines(&old_lines, &new_lines).iter().for_each(|change| {
-                    match change {
-                        diff::Result::Left(l) => diff_output.push(diff::Result::Left(l.to_string())),
-                        diff::Result::Both(l, r) => diff_output.push(diff::Result::Both(l.to_string(), r.to_string())),
-                        diff::Result::Right(r) => diff_output.push(diff::Result::Right(r.to_string())),
-                    }
-                });
-
-                notifs.diff = diff_output;
-                buffer.push(notifs);
+            if !logged {
+                warn!(root = %root.display(), "waiting for the root to be created");
+                logged = true;
             }
-        }
+            debug!(root = %root.display(), retry_in_ms = backoff.as_millis() as u64, "root doesn't exist yet");
 
-        self.elapsed = elapsed;
-        
-        for child in &mut self.children {
-            child.poll(buffer);
+            tokio::select! {
+                _ = tokio::time::sleep(backoff) => {},
+                _ = tokio::signal::ctrl_c() => std::process::exit(130)
+            }
+            backoff = (backoff * 2).min(ROOT_WAIT_MAX);
+        };
+
+        if logged {
+            info!(root = %canonical.display(), "root created, watching it");
         }
+        *root = canonical;
     }
 }
 
-struct Notification {
-    time: SystemTime, 
-    path: PathBuf,
-    diff: Vec<diff::Result<String>>
-}
-
-impl Notification {
-    fn new(path: &Path) -> Self {
-        Self {
-            time: SystemTime::now(),
-            path: path.to_path_buf().clone(),
-            diff: Vec::new()
-        }
+/// Ask the watcher to stop on the first Ctrl-C, and exit right away on the second
+async fn handle_ctrl_c(quiet: bool) {
+    if tokio::signal::ctrl_c().await.is_err() {
+        return;
     }
 
-    fn format_system_time(time: &SystemTime) -> String {
-        let datetime = match time.duration_since(UNIX_EPOCH) {
-            Ok(duration) => {
-                let secs = duration.as_secs() as i64;
-                let nanos = duration.subsec_nanos();
-                DateTime::<Utc>::from_timestamp(secs, nanos).unwrap()
-            },
-            Err(_) => {
-                Utc::now() 
-            }
-        };
-
-        let local_time: DateTime<Local> = DateTime::from(datetime);
-        local_time.format("%Y-%m-%d %H:%M:%S").to_string()
+    if !quiet {
+        eprintln!("Shutting down, press Ctrl-C again to exit immediately");
     }
+    watcher::shutdown();
 
-    fn display(&self) {
-        println!("[{}] - {}", Notification::format_system_time(&self.time), self.path.as_os_str().to_str().unwrap());
-        let mut count: u64 = 0;
-        let _ = &self.diff.iter().for_each(|diff| {
-            count += 1;
-            match diff {
-                diff::Result::Left(l) => {
-                    println!("{:0>5} - |  {}", count, l);
-                },
-                diff::Result::Right(r) => {
-                    println!("{:0>5} + |  {}", count, r);
-                }
-                _ => {},
-            }
-        });
+    if tokio::signal::ctrl_c().await.is_ok() {
+        std::process::exit(130);
     }
+}
 
-    fn json(&self) -> String {
-        let datetime: DateTime<Utc> = self.time.into();
-        let rfc_dt = datetime.to_rfc3339();
-
-        #[derive(serde::Serialize, serde::Deserialize)]
-        struct Change {
-            direction: i8,
-            change: String
-        }
+/// Send the logs to stderr, keeping stdout for the notifications themselves
+fn init_logging(verbose: u8, quiet: bool) {
+    let level = match verbose {
+        0 if quiet => Level::ERROR,
+        0 => Level::WARN,
+        1 => Level::INFO,
+        2 => Level::DEBUG,
+        _ => Level::TRACE
+    };
+
+    tracing_subscriber::fmt()
+        .with_writer(std::io::stderr)
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_max_level(level)
+        .init();
+}
 
-        let mut diff_result: Vec<Change> = Vec::new();
+/// Scan the roots, exiting when the watcher can't be set up
+fn start(config: Config) -> Watcher {
+    Watcher::new(config).unwrap_or_else(|e| {
+        eprintln!("Cannot watch: {}", e);
+        std::process::exit(1);
+    })
+}
 
-        self.diff.iter().for_each(|change| {
-            match change {
-                diff::Result::Left(l) => diff_result.push(Change{direction: -1, change: l.to_string()}),
-                diff::Result::Right(r) => diff_result.push(Change{direction: 1, change: r.to_string()}),
-                diff::Result::Both(l, _) => diff_result.push(Change{direction: 0, change: l.to_string()}),
-            }
-        });
+/// Serve the status, the metrics and the event stream, and post the
+/// heartbeats, on the addresses and the interval of the config
+fn serve(config: &Config) {
+    if let Some(addr) = &config.status_addr {
+        tokio::spawn(watcher::serve_status(addr.clone()));
+    }
 
-        let json = serde_json::json! ({
-            "time": rfc_dt,
-            "path": self.path.to_str(),
-            "diff": diff_result
-        });
+    if let Some(addr) = &config.metrics_addr {
+        tokio::spawn(watcher::serve_metrics(addr.clone()));
+    }
 
-        serde_json::to_string(&json).unwrap()
+    if let Some(addr) = &config.sse_addr {
+        tokio::spawn(watcher::serve_events(addr.clone()));
     }
 
-    async fn notify(&self) -> Result<(), reqwest::Error> {
-        let client = reqwest::Client::new();
-        
-        for endpoint in &CONFIG.endpoints {
-            client.post(endpoint)
-                .body(self.json())
-                .send()
-                .await?;
-        }
-        
-        Ok(())
+    if let Some(secs) = config.heartbeat_interval_secs {
+        tokio::spawn(watcher::heartbeat(Duration::from_secs(secs)));
     }
 }
 
-struct FileTree {
-    head: Box<Node>,
+/// Whether the notifications are printed for humans and as JSON lines
+fn printed(config: &Config) -> (bool, bool) {
+    match config.output.as_str() {
+        _ if config.quiet => (false, false),
+        "json" => (false, true),
+        "both" => (true, true),
+        _ => (true, false)
+    }
 }
 
-impl FileTree {
-    fn new() -> FileTree {
-        FileTree { head: Box::new(Node::new()) }
+/// Deliver the notifications to the endpoints as they come in, after the
+/// ones that failed on the last run, batching the ones that queued up while
+/// the previous delivery was in flight when the config in effect says so. Up
+/// to `concurrency` deliveries are in flight at once
+async fn deliver(mut rx: mpsc::UnboundedReceiver<Notification>, concurrency: usize) {
+    if !watcher::current_config().dry_run {
+        watcher::redeliver_dead_letters().await;
     }
 
-    fn fill(&mut self) {
-        let cwd = env::current_dir().expect("Current directory retrieval failed");
-        self.head.fill(cwd.as_path());
-    }
+    let permits = Arc::new(Semaphore::new(concurrency));
+    let mut deliveries = JoinSet::new();
 
-    #[allow(dead_code)]
-    fn display(&self) {
-        self.head.display("");
+    while let Some(notif) = rx.recv().await {
+        let mut notifs = vec![notif];
+        while let Ok(notif) = rx.try_recv() {
+            notifs.push(notif);
+        }
+
+        let batch = watcher::current_config().batch_notifications;
+        let groups = if batch { vec![notifs] } else { notifs.into_iter().map(|notif| vec![notif]).collect() };
+        for notifs in groups {
+            let permit = permits.clone().acquire_owned().await.expect("Semaphore closed");
+            deliveries.spawn(async move {
+                let result = match notifs.as_slice() {
+                    [notif] if !batch => notif.notify().await,
+                    notifs => Notification::notify_batch(notifs).await
+                };
+                if let Err(e) = result {
+                    error!("{}", e);
+                }
+                drop(permit);
+            });
+
+            // Reap the finished ones so they don't pile up
+            while deliveries.try_join_next().is_some() {}
+        }
     }
+
+    while deliveries.join_next().await.is_some() {}
+    watcher::close_sockets().await;
 }
 
 #[tokio::main]
 async fn main() {
-    let mut ft = FileTree::new();
-    ft.fill();
+    let cli = Cli::parse();
+    if let Some(Command::Init { force }) = cli.command {
+        init(&config_path(&cli), force);
+        return;
+    }
 
-    let mut notifications: Vec<Notification> = Vec::new();
-    let mut cycle = 0;
-    const TREE_REBUILD_CYCLE: usize = 1000;
+    let mut config = load_config(&cli);
+    if let Some(Command::TestEndpoints) = cli.command {
+        test_endpoints(config).await;
+        return;
+    }
+    if let Some(Command::Check { save }) = cli.command {
+        check(config, save);
+        return;
+    }
 
-    loop {
-        cycle += 1;
+    // The payloads of a dry run are logged at the info level, so they have to
+    // show up. Logs would draw over the terminal UI, so there are none with it
+    if !cli.tui {
+        init_logging(if config.dry_run { cli.verbose.max(1) } else { cli.verbose }, config.quiet);
+    }
 
-        ft.head.poll(&mut notifications);
-        if let Some(notif) = notifications.pop() {
-            notif.display();
-            let _ = notif.notify().await;
+    if config.wait_for_root {
+        wait_for_roots(&mut config.roots).await;
+    }
+
+    let concurrency = config.max_concurrent_deliveries;
+    let quiet = config.quiet;
+
+    if cli.once {
+        for root in &config.roots {
+            let readable = if root.is_dir() { root.read_dir().map(drop) } else { std::fs::File::open(root).map(drop) };
+            if let Err(e) = readable {
+                eprintln!("Cannot read {}: {}", root.display(), e);
+                std::process::exit(1);
+            }
         }
 
-        if cycle == TREE_REBUILD_CYCLE {
-            ft = FileTree::new();
-            ft.fill();
+        let watcher = start(config);
+        if !quiet {
+            watcher.display();
+        }
+        return;
+    }
+
+    let mut watcher = start(config);
+    serve(&watcher::current_config());
+    tokio::spawn(handle_ctrl_c(quiet));
+    #[cfg(unix)]
+    tokio::spawn(reload_on_hangup(cli.clone()));
 
-            cycle = 0;
+    let (tx, rx) = mpsc::unbounded_channel();
+    let delivery = tokio::spawn(deliver(rx, concurrency));
+
+    // With the terminal UI the notifications go to its log instead of stdout
+    let mut view = None;
+    let mut log_tx = None;
+    if cli.tui {
+        let (trees_tx, trees_rx) = watch::channel(Vec::new());
+        let (notifs_tx, notifs_rx) = mpsc::unbounded_channel();
+
+        watcher = watcher.on_sync(move |trees| {
+            let _ = trees_tx.send(trees.iter().flat_map(FileTree::lines).collect());
+        });
+        view = Some(tokio::spawn(tui::run(trees_rx, notifs_rx)));
+        log_tx = Some(notifs_tx);
+    }
+
+    let result = watcher.watch(move |notif| {
+        if let Some(log_tx) = &log_tx {
+            let _ = log_tx.send((notif.kind.clone(), notif.simple(false)));
+        } else {
+            // Read every time, as a reload may change them
+            let (human, json) = printed(&watcher::current_config());
+            if human {
+                notif.display();
+            }
+
+            // One complete object per line, flushed right away for consumers like `jq`
+            if json {
+                let mut stdout = std::io::stdout().lock();
+                let _ = writeln!(stdout, "{}", notif.json());
+                let _ = stdout.flush();
+            }
         }
 
-        sleep(Duration::new(0, TIMEPERIOD));
-        
+        let _ = tx.send(notif);
+    }).await;
+
+    // The view stops along with the watcher and restores the terminal
+    if let Some(view) = view {
+        let _ = view.await;
     }
-}
 
-/* TODO: Potential bug:
- *
- * If the tree is taking a long time to construct, a tree can take more time to
- * construct itself than the time period mentioned.
- *
- * If that is the case, even if a modification is done to a file, for the next iteration,
- * the tree would take more time than the threshold for a file modification. The 
- * last_modification time for the file would have been expired by then and the file
- * could be marked as an unmodified file.
- *
- * Potential solutions:
- * - Add the time to construct the tree as an offset to the original timeperiod ❌
- * OR
- * - Compare the last modification time for both the versions, if they are different, it is
- * modified ✅
- *
- * The latter seems like the better approach.
- * 
- */
-
-/* TODO: Inner data model fix
- *
- * Instead of relying on two snapshots that we are creating, we can create one snapshot of the
- * filesystem and whenever it needs to update itself, we create a diff when we compare the tree
- * with its new version. This saves space and time.
- *
- */
+    // The callback owned the sender, so the delivery ends once it has caught up
+    let _ = delivery.await;
 
+    if let Err(e) = result {
+        eprintln!("Stopped watching: {}", e);
+        std::process::exit(1);
+    }
+}
@@ -0,0 +1,117 @@
+use std::{collections::VecDeque, time::{Duration, SystemTime}};
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers},
+    layout::{Constraint, Layout},
+    style::{Color, Style},
+    text::Line,
+    widgets::{Block, Paragraph},
+    DefaultTerminal, Frame
+};
+use tokio::sync::{mpsc, watch};
+use watcher::{NotificationKind, TreeLine};
+
+/// Lines of notifications kept around for the log pane
+const MAX_LOG_LINES: usize = 1000;
+
+/// How often the ages are refreshed when nothing else happens
+const TICK: Duration = Duration::from_secs(1);
+
+/// How long ago a file was modified, in its largest unit
+fn age(modified: SystemTime) -> String {
+    let secs = SystemTime::now().duration_since(modified).map_or(0, |t| t.as_secs());
+    match secs {
+        0..60 => format!("{}s", secs),
+        60..3600 => format!("{}m", secs / 60),
+        3600..86400 => format!("{}h", secs / 3600),
+        _ => format!("{}d", secs / 86400)
+    }
+}
+
+/// Color of the lines of a notification, by what happened to the file
+fn style(kind: &NotificationKind) -> Style {
+    match kind {
+        NotificationKind::Deleted | NotificationKind::FolderDeleted => Style::new().fg(Color::Red),
+        NotificationKind::Created => Style::new().fg(Color::Green),
+        NotificationKind::Modified | NotificationKind::Renamed { .. } | NotificationKind::Metadata { .. } => Style::new().fg(Color::Yellow),
+        _ => Style::new()
+    }
+}
+
+fn draw(frame: &mut Frame, tree: &[TreeLine], log: &VecDeque<(Style, String)>) {
+    let [tree_area, log_area] = Layout::horizontal([Constraint::Percentage(40), Constraint::Percentage(60)])
+        .areas(frame.area());
+
+    let tree_lines: Vec<Line> = tree.iter().map(|line| {
+        let age = line.modified.map(age).unwrap_or_default();
+        Line::from(format!("{}└── {}  {}", "│  ".repeat(line.depth), line.name, age))
+    }).collect();
+    frame.render_widget(Paragraph::new(tree_lines).block(Block::bordered().title(" Files ")), tree_area);
+
+    // Only the latest lines that fit inside the borders
+    let height = log_area.height.saturating_sub(2) as usize;
+    let log_lines: Vec<Line> = log.iter().skip(log.len().saturating_sub(height))
+        .map(|(style, line)| Line::styled(line.as_str(), *style))
+        .collect();
+    frame.render_widget(Paragraph::new(log_lines).block(Block::bordered().title(" Changes (q to quit) ")), log_area);
+}
+
+/// Forward the key presses from a blocking thread, until the view is gone
+fn read_keys(tx: mpsc::UnboundedSender<KeyEvent>) {
+    while !tx.is_closed() {
+        if !event::poll(Duration::from_millis(100)).unwrap_or(false) {
+            continue;
+        }
+
+        if let Ok(Event::Key(key)) = event::read() {
+            if key.kind == KeyEventKind::Press && tx.send(key).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+async fn draw_until_done(
+    terminal: &mut DefaultTerminal,
+    mut trees: watch::Receiver<Vec<TreeLine>>,
+    mut notifs: mpsc::UnboundedReceiver<(NotificationKind, String)>
+) {
+    let (key_tx, mut keys) = mpsc::unbounded_channel();
+    std::thread::spawn(move || read_keys(key_tx));
+
+    let mut log: VecDeque<(Style, String)> = VecDeque::new();
+    let mut tick = tokio::time::interval(TICK);
+
+    loop {
+        tokio::select! {
+            changed = trees.changed() => if changed.is_err() {
+                break
+            },
+            Some((kind, text)) = notifs.recv() => {
+                let style = style(&kind);
+                log.extend(text.lines().map(|line| (style, line.to_string())));
+                while log.len() > MAX_LOG_LINES {
+                    log.pop_front();
+                }
+            },
+            Some(key) = keys.recv() => {
+                let quit = matches!(key.code, KeyCode::Char('q') | KeyCode::Esc)
+                    || (key.code == KeyCode::Char('c') && key.modifiers.contains(KeyModifiers::CONTROL));
+                if quit {
+                    watcher::shutdown();
+                }
+            },
+            _ = tick.tick() => {}
+        }
+
+        let tree = trees.borrow();
+        let _ = terminal.draw(|frame| draw(frame, &tree, &log));
+    }
+}
+
+/// Show the trees and the notifications live in the terminal until the
+/// watcher stops, which `q` asks it to
+pub async fn run(trees: watch::Receiver<Vec<TreeLine>>, notifs: mpsc::UnboundedReceiver<(NotificationKind, String)>) {
+    let mut terminal = ratatui::init();
+    draw_until_done(&mut terminal, trees, notifs).await;
+    ratatui::restore();
+}